@@ -1,16 +1,22 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{collections::{BTreeMap, BTreeSet}, sync::Arc};
 
-use futures::{TryStreamExt};
-use k8s_openapi::api::apps::v1::Deployment;
-use kube::{runtime::watcher, Client, Api, api::ListParams, ResourceExt};
-use tokio::{sync::{mpsc::Sender, RwLock}, task::JoinHandle};
-use tracing::{debug, info, warn};
+use tokio::{sync::RwLock, task::JoinHandle};
+use tracing::info;
+
+use crate::controller::{self, Context};
+use crate::finalizer::{ORIGINAL_REPLICAS, TEARDOWN_CONFIG};
+use crate::metrics;
+use crate::status::STATUS_ANNOTATION;
+
+// written by the operator itself, so they don't mark a deployment as managed
+pub const OPERATOR_ANNOTATIONS: [&str; 3] = [STATUS_ANNOTATION, ORIGINAL_REPLICAS, TEARDOWN_CONFIG];
 
 #[derive(Clone)]
 pub enum State {
   Added,
-  Modified,
   Deleted,
+  // every namespace of the initial list was announced before
+  Listed,
 }
 #[derive(Clone)]
 pub enum AppType {
@@ -27,81 +33,72 @@ pub struct ChangeObject<T> {
 
 #[derive(Clone)]
 pub struct AnnotationWatcher {
-  pub namespace: Vec<String>,
   handler: Arc<RwLock<BTreeMap<String, JoinHandle<()>>>>,
-  tx: Sender<ChangeObject<Deployment>>,
+  // namespaces whose controllers completed their initial list
+  listed: Arc<RwLock<BTreeSet<String>>>,
+  ctx: Arc<Context>,
 }
 
 impl AnnotationWatcher {
-  pub fn new(tx: Sender<ChangeObject<Deployment>>) -> AnnotationWatcher {
-    let t = AnnotationWatcher {
-      namespace: vec![],
+  pub fn new(ctx: Arc<Context>) -> AnnotationWatcher {
+    AnnotationWatcher {
       handler: Arc::new(RwLock::new(BTreeMap::new())),
-      tx: tx,
-    };
-    t
+      listed: Arc::new(RwLock::new(BTreeSet::new())),
+      ctx,
+    }
   }
 
   pub async fn remove_ns(&self, name: String) {
-    match self.handler.read().await.get(&name) {
-        Some(v) => {v.abort();},
-        None => {},
+    if let Some(v) = self.handler.write().await.remove(&name) {
+      info!("stopping controllers for ns {}", name);
+      v.abort();
     }
+    self.listed.write().await.remove(&name);
   }
 
   pub async fn add_ns(&mut self, namespace: String) {
+    let mut handler = self.handler.write().await;
+    match handler.get(&namespace) {
+      Some(h) if !h.is_finished() => return,
+      Some(_) => {
+        info!("restarting controllers for ns {}", namespace);
+        metrics::WATCHER_RESTARTS.with_label_values(&[&namespace]).inc();
+      },
+      None => {},
+    }
     info!("spawn");
-    // todo: implement first state check
-    let tx = self.tx.clone();
-    let ns = namespace.clone();
-    // todo: handle JoinHandle
-    let handler = tokio::spawn(async move {
-      let client = match Client::try_default().await {
-          Ok(v) => v,
-          Err(e) => {warn!("failed creating client {}", e); return},
-      };
-      let deployments: Api<Deployment> = Api::namespaced(client, &namespace);
-      info!("starting watcher for ns {}", namespace.clone());
-      let watch = watcher(deployments, ListParams::default())
-        .try_for_each(|e| async {
-          debug!("got watch event");
-          match e {
-            watcher::Event::Applied(d) => {
-              match get_type(d.annotations()) {
-                AppType::SeroSelf => {
-                  info!("add sero self: {}", d.clone().metadata.name.unwrap());},
-                AppType::Managed => {
-                  info!("add event: {}", d.clone().metadata.name.unwrap());
-                  _ = tx.send(ChangeObject { object: d, state: State::Added }).await;
-                },
-                AppType::NotManaged => {_ = tx.send(ChangeObject { object: d, state: State::Deleted }).await;},
-              }
-            },
-            watcher::Event::Deleted(d) => {
-              match get_type(d.annotations()) {
-                AppType::SeroSelf => {},
-                _ => {_ = tx.send(ChangeObject { object: d, state: State::Deleted }).await;}
-              }
-            },
-            // todo: implement ::Restarted
-            _ => {},
-          };
-          Ok(())
-        })
-        ;
-      match watch.await {
-          Ok(_) => {},
-          Err(e) => {warn!("err {}", e)},
-      };
-      info!("started watcher");
-    });
-    self.handler.write().await.insert(ns,handler);
+    self.listed.write().await.remove(&namespace);
+    let ctx = self.ctx.clone();
+    let listed = self.listed.clone();
+    handler.insert(namespace.clone(), tokio::spawn(controller::run(namespace, ctx, listed)));
+  }
+
+  /// Namespaces controllers run for.
+  pub async fn namespaces(&self) -> BTreeSet<String> {
+    self.handler.read().await.keys().cloned().collect()
+  }
+
+  /// Namespaces whose controllers are still waiting for their initial list.
+  pub async fn pending(&self) -> Vec<String> {
+    let listed = self.listed.read().await;
+    self.handler.read().await.keys()
+      .filter(|ns| !listed.contains(*ns))
+      .cloned()
+      .collect()
+  }
+
+  /// Namespaces whose controllers stopped and weren't restarted.
+  pub async fn dead(&self) -> Vec<String> {
+    self.handler.read().await.iter()
+      .filter(|(_, h)| h.is_finished())
+      .map(|(ns, _)| ns.clone())
+      .collect()
   }
 }
 
 pub fn get_type(annotations: &BTreeMap<String, String>) -> AppType {
   let has_annotation = annotations.keys().any(|e| {
-    e.contains("beta.v1.sero/")
+    e.contains("beta.v1.sero/") && !OPERATOR_ANNOTATIONS.contains(&e.as_str())
   });
   let has_config = annotations.keys().any(|e| {
     e == &String::from("beta.v1.sero/config")
@@ -111,5 +108,5 @@ pub fn get_type(annotations: &BTreeMap<String, String>) -> AppType {
   } else if has_annotation && !has_config {
     return AppType::Managed;
   }
-  return AppType::NotManaged;
+  AppType::NotManaged
 }
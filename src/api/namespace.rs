@@ -1,66 +1,84 @@
-use std::{collections::BTreeMap};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
 
-use futures::TryStreamExt;
+use backoff::ExponentialBackoff;
+use futures::StreamExt;
 use k8s_openapi::api::core::v1::Namespace;
-use kube::{runtime::watcher, Client, Api, api::ListParams, ResourceExt};
-use tokio::sync::mpsc::Sender;
+use kube::{runtime::{watcher, WatchStreamExt}, Client, Api, api::ListParams, ResourceExt};
+use tokio::{sync::{mpsc::Sender, Mutex}, task::JoinHandle};
 use tracing::{info, warn};
 
 use super::annotation::{ChangeObject, State};
+use crate::metrics;
 
-pub async fn spawn(tx: Sender<ChangeObject<String>>) {
+/// Announces annotated namespaces on `tx`, followed by `State::Listed` once the initial list was announced.
+pub fn spawn(client: Client, tx: Sender<ChangeObject<String>>) -> JoinHandle<()> {
   info!("spawn");
-  // todo: implement first state check
-  // todo: handle JoinHandle
-  _ = tokio::spawn(async move {
-    let client = Client::try_default().await.unwrap();
+  tokio::spawn(async move {
     let ns: Api<Namespace> = Api::all(client);
+    // namespaces currently announced as watched
+    let known = Arc::new(Mutex::new(BTreeSet::<String>::new()));
     info!("starting watcher");
-    match ns.list_metadata(&ListParams::default()).await {
-        Ok(e) => {
-          for e in e.items.into_iter()
-              .filter(|e| {e.annotations().contains_key("beta.v1.sero/scan")})
-              .map(|e| {e.metadata.name.unwrap()}) {
-            info!("send msg {}", e);
-            let r = tx.send(ChangeObject { object: e, state: State::Added }).await;
-            if r.is_err() {
-              warn!("e: {}", r.unwrap_err());
-            }
-          }
-        },
-        Err(_) => {return;},
-    };
+    // keep watching after errors, the relist afterwards is handled as ::Restarted
+    let backoff = ExponentialBackoff { max_elapsed_time: None, ..Default::default() };
     let watch = watcher(ns, ListParams::default())
-      .try_for_each(|e| async {
+      .backoff(backoff)
+      .for_each(|e| { let tx = tx.clone(); let known = known.clone(); async move {
+        let e = match e {
+          Ok(e) => e,
+          Err(e) => {warn!("err {}", e); return},
+        };
         info!("got ns event");
+        let mut known = known.lock().await;
         match e {
           watcher::Event::Applied(d) => {
+            let name = d.name_any();
             if has_sero_annotation(d.annotations()) {
-              let name = d.metadata.name.unwrap();
               info!("add ns event: {}", name);
-              _ = tx.send(ChangeObject { object: name, state: State::Added }).await;
-            } else {
-              _ = tx.send(ChangeObject { object: d.metadata.name.unwrap(), state: State::Deleted }).await;
+              known.insert(name.clone());
+              announce(&tx, ChangeObject { object: name, state: State::Added }).await;
+            } else if known.remove(&name) {
+              info!("remove ns event: {}", name);
+              announce(&tx, ChangeObject { object: name, state: State::Deleted }).await;
             }
           },
           watcher::Event::Deleted(d) => {
-            if has_sero_annotation(d.annotations()) {
-              let name = d.metadata.name.unwrap();
+            let name = d.name_any();
+            if known.remove(&name) {
+              info!("remove ns event: {}", name);
+              announce(&tx, ChangeObject { object: name, state: State::Deleted }).await;
+            }
+          },
+          // initial list and relists after a reconnect: everything not listed anymore was missed
+          watcher::Event::Restarted(list) => {
+            let current: BTreeSet<String> = list.iter()
+              .filter(|d| has_sero_annotation(d.annotations()))
+              .map(|d| d.name_any())
+              .collect();
+            info!("resync of {} watched ns", current.len());
+            for name in known.difference(&current) {
               info!("remove ns event: {}", name);
-              _ = tx.send(ChangeObject { object: name, state: State::Deleted }).await;
-            }},
-          // todo: implement ::Restarted
-          _ => {},
+              announce(&tx, ChangeObject { object: name.clone(), state: State::Deleted }).await;
+            }
+            for name in current.iter() {
+              announce(&tx, ChangeObject { object: name.clone(), state: State::Added }).await;
+            }
+            *known = current;
+            announce(&tx, ChangeObject { object: String::new(), state: State::Listed }).await;
+          },
         };
-        Ok(())
-      })
+      }})
       ;
-    match watch.await {
-        Ok(_) => {},
-        Err(e) => {warn!("err {}", e)},
-    };
-    info!("started watcher");
-  });
+    watch.await;
+    info!("stopped watcher");
+  })
+}
+
+async fn announce(tx: &Sender<ChangeObject<String>>, co: ChangeObject<String>) {
+  if let Err(e) = tx.send(co).await {
+    warn!("announcing ns event failed: {}", e);
+  }
+  metrics::EVENT_QUEUE_DEPTH.set((tx.max_capacity() - tx.capacity()) as i64);
 }
 
 pub fn has_sero_annotation(annotations: &BTreeMap<String, String>) -> bool{
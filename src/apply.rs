@@ -0,0 +1,332 @@
+use anyhow::Result;
+use k8s_openapi::NamespaceResourceScope;
+use kube::api::{DeleteParams, Patch, PatchParams, PostParams};
+use kube::{Api, Client, Resource, ResourceExt};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tracing::{debug, info, warn};
+
+use crate::metrics;
+use crate::operator_config::{ApplySettings, DryRun};
+
+// hash of the desired state an object was last applied with
+pub const DESIRED_HASH: &str = "beta.v1.sero/desired-hash";
+
+/// What `create_or_update` found.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Applied {
+    Created,
+    Unchanged,
+    /// the desired state changed
+    Updated,
+    /// the live object was changed by someone else, it is only applied again with `repair`
+    Drifted,
+    /// the object was deleted by someone else, it is only created again with `repair`
+    Deleted,
+}
+
+/// Server-side applies `t`, skipping the write if the live object already matches.
+///
+/// `recorded` is the desired hash the object was last applied with, if it was applied before.
+pub async fn create_or_update<T>(client: &Client, t: &T, name: &str, namespace: &str, settings: &ApplySettings, repair: bool, recorded: Option<&String>) -> Result<Applied>
+where
+    <T as kube::Resource>::DynamicType: Default,
+    T: kube::Resource<Scope = NamespaceResourceScope> + Clone + DeserializeOwned + Serialize + std::fmt::Debug,
+{
+    let api: Api<T> = Api::<T>::namespaced(client.clone(), namespace);
+    let kind = T::kind(&Default::default()).to_string();
+
+    let mut t = t.clone();
+    set_desired_hash(&mut t);
+    let hash = t.annotations()[DESIRED_HASH].clone();
+    let t = &t;
+
+    let current = api.get_opt(name).await.inspect_err(|_| metrics::api_error(&kind, "get"))?;
+    let applied = match current {
+        // it was there with the same desired state, so someone else deleted it
+        None if recorded == Some(&hash) => {
+            let action = if repair { "repaired" } else { "reported" };
+            warn!("{} {}/{} was deleted, {}", kind, namespace, name, action);
+            metrics::DRIFT.with_label_values(&[namespace, &kind, action]).inc();
+            if !repair {
+                return Ok(Applied::Deleted);
+            }
+            Applied::Deleted
+        },
+        None => Applied::Created,
+        Some(current) if is_subset(&serde_json::to_value(t)?, &serde_json::to_value(&current)?) => {
+            debug!("{} is up to date", name);
+            return Ok(Applied::Unchanged);
+        },
+        // the desired state didn't change since the last apply, so the difference was made by someone else
+        Some(current) if current.annotations().get(DESIRED_HASH) == Some(&hash) => {
+            let action = if repair { "repaired" } else { "reported" };
+            warn!("{} {}/{} drifted from the desired state, {}", kind, namespace, name, action);
+            metrics::DRIFT.with_label_values(&[namespace, &kind, action]).inc();
+            if !repair {
+                return Ok(Applied::Drifted);
+            }
+            Applied::Drifted
+        },
+        Some(_) => Applied::Updated,
+    };
+    let verb = if matches!(applied, Applied::Created | Applied::Deleted) { "create" } else { "update" };
+    let mut params = match patch_params(settings, verb, &kind, namespace, name) {
+        Some(v) => v,
+        None => return Ok(applied),
+    };
+    params.field_manager = Some(settings.field_manager.clone());
+    params.force = settings.force;
+    api.patch(name, &params, &Patch::Apply(t)).await.inspect_err(|_| metrics::api_error(&kind, "apply"))?;
+    Ok(applied)
+}
+
+/// Annotates `t` with the hash of its desired state.
+///
+/// Owner references are left out, so rendered and applied objects carry the same hash.
+pub fn set_desired_hash<T: Resource + Serialize>(t: &mut T) {
+    t.annotations_mut().remove(DESIRED_HASH);
+    // kubernetes objects always serialize, the keys of a json value are sorted
+    let mut value = serde_json::to_value(&*t).unwrap();
+    if let Some(meta) = value.get_mut("metadata").and_then(Value::as_object_mut) {
+        meta.remove("ownerReferences");
+    }
+    let hash = fnv1a(value.to_string().as_bytes());
+    t.annotations_mut().insert(DESIRED_HASH.to_string(), format!("{:016x}", hash));
+}
+
+// stable across builds and versions, unlike the hasher of std
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Whether every field set in `desired` has the same value in `actual`.
+///
+/// Fields only present in `actual` (defaults, status, fields of other managers) are ignored.
+/// The api server drops empty maps and lists and canonicalizes quantities, so those compare by meaning.
+pub fn is_subset(desired: &Value, actual: &Value) -> bool {
+    subset(desired, actual, false)
+}
+
+// `quantities` marks maps of quantities, the limits and requests of resources
+fn subset(desired: &Value, actual: &Value, quantities: bool) -> bool {
+    match (desired, actual) {
+        (Value::Object(d), Value::Object(a)) => d.iter().all(|(k, v)| match (a.get(k), v) {
+            (Some(Value::String(av)), Value::String(dv)) if quantities && av != dv => {
+                matches!((quantity(dv), quantity(av)), (Some(d), Some(a)) if d == a)
+            },
+            (Some(av), _) => subset(v, av, k == "limits" || k == "requests"),
+            (None, _) => is_empty(v),
+        }),
+        (Value::Array(d), Value::Array(a)) => {
+            d.len() == a.len() && d.iter().zip(a).all(|(dv, av)| subset(dv, av, false))
+        },
+        (d, Value::Null) => is_empty(d),
+        (d, a) => d == a,
+    }
+}
+
+fn is_empty(v: &Value) -> bool {
+    match v {
+        Value::Null => true,
+        Value::Object(o) => o.is_empty(),
+        Value::Array(a) => a.is_empty(),
+        _ => false,
+    }
+}
+
+/// A kubernetes quantity (`500m`, `0.5`, `1Gi`, `1e3`) in billionths, `None` if it doesn't parse.
+fn quantity(s: &str) -> Option<i128> {
+    let s = s.trim();
+    let end = s.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '+' || c == '-')).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(end);
+    let (int, frac) = number.split_once('.').unwrap_or((number, ""));
+    let negative = int.starts_with('-');
+    let int = int.trim_start_matches(['+', '-']);
+    if (int.is_empty() && frac.is_empty()) || !frac.chars().all(|c| c.is_ascii_digit()) || !int.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    // the number as an integer with `frac.len()` decimal places
+    let digits: i128 = format!("{}{}", int, frac).parse().ok()?;
+    let (base, exp): (i128, u32) = match suffix {
+        "Ki" => (1024, 1), "Mi" => (1024, 2), "Gi" => (1024, 3), "Ti" => (1024, 4), "Pi" => (1024, 5), "Ei" => (1024, 6),
+        "n" => (10, 0), "u" => (10, 3), "m" => (10, 6), "" => (10, 9),
+        "k" => (10, 12), "M" => (10, 15), "G" => (10, 18), "T" => (10, 21), "P" => (10, 24), "E" => (10, 27),
+        e if e.starts_with(['e', 'E']) => {
+            let exp: i32 = e[1..].parse().ok()?;
+            let nanos = 10i128.checked_pow(u32::try_from(exp + 9).ok()?)?;
+            return signed(digits.checked_mul(nanos)? / 10i128.checked_pow(frac.len() as u32)?, negative);
+        },
+        _ => return None,
+    };
+    // binary suffixes are whole units, scaled to billionths
+    let factor = match base {
+        1024 => 1024i128.checked_pow(exp)?.checked_mul(1_000_000_000)?,
+        _ => 10i128.checked_pow(exp)?,
+    };
+    signed(digits.checked_mul(factor)? / 10i128.checked_pow(frac.len() as u32)?, negative)
+}
+
+fn signed(v: i128, negative: bool) -> Option<i128> {
+    Some(if negative { -v } else { v })
+}
+
+pub async fn delete_owned<T>(api: &Api<T>, name: &str, owner_uid: &str, settings: &ApplySettings) -> Result<()>
+where
+    T: kube::Resource + Clone + DeserializeOwned + std::fmt::Debug,
+    <T as kube::Resource>::DynamicType: Default,
+{
+    let kind = T::kind(&Default::default()).to_string();
+    let meta = api.get_metadata_opt(name).await.inspect_err(|_| metrics::api_error(&kind, "get"))?;
+    let meta = match meta {
+        Some(v) => v.metadata,
+        None => return Ok(()),
+    };
+    let owned = meta.owner_references.unwrap_or_default().iter().any(|o| o.uid == owner_uid);
+    if !owned {
+        warn!("refusing to delete {}: not owned by {}", name, owner_uid);
+        return Ok(());
+    }
+    let params = match delete_params(settings, &kind, &meta.namespace.unwrap_or_default(), name) {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    api.delete(name, &params).await.inspect_err(|_| metrics::api_error(&kind, "delete"))?;
+    Ok(())
+}
+
+/// Params of a patch, `None` if it is skipped in dry-run mode.
+pub fn patch_params(settings: &ApplySettings, verb: &str, kind: &str, namespace: &str, name: &str) -> Option<PatchParams> {
+    dry_run(settings, verb, kind, namespace, name).map(|dry_run| PatchParams { dry_run, ..Default::default() })
+}
+
+/// Params of a create or replace, `None` if it is skipped in dry-run mode.
+pub fn post_params(settings: &ApplySettings, verb: &str, kind: &str, namespace: &str, name: &str) -> Option<PostParams> {
+    dry_run(settings, verb, kind, namespace, name).map(|dry_run| PostParams { dry_run, ..Default::default() })
+}
+
+/// Params of a delete, `None` if it is skipped in dry-run mode.
+pub fn delete_params(settings: &ApplySettings, kind: &str, namespace: &str, name: &str) -> Option<DeleteParams> {
+    dry_run(settings, "delete", kind, namespace, name).map(|dry_run| DeleteParams { dry_run, ..DeleteParams::background() })
+}
+
+/// Records a write in dry-run mode, returns whether it is sent with `dryRun=All` or `None` if it is skipped.
+pub fn dry_run(settings: &ApplySettings, verb: &str, kind: &str, namespace: &str, name: &str) -> Option<bool> {
+    if settings.dry_run == DryRun::None {
+        return Some(false);
+    }
+    info!(target: "sero_operator::dry_run", mode = ?settings.dry_run, verb, kind, namespace, name,
+        "dry run: {} {} {}/{}", verb, kind, namespace, name);
+    metrics::DRY_RUN_WRITES.with_label_values(&[kind, verb]).inc();
+    match settings.dry_run {
+        DryRun::Server => Some(true),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+    use kube::core::ObjectMeta;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn fields_added_by_the_server_are_ignored() {
+        let desired = json!({"metadata": {"name": "sero-app"}, "spec": {"replicas": 1}});
+        let actual = json!({
+            "metadata": {"name": "sero-app", "uid": "1", "resourceVersion": "2"},
+            "spec": {"replicas": 1, "revisionHistoryLimit": 10},
+            "status": {"availableReplicas": 1},
+        });
+        assert!(is_subset(&desired, &actual));
+    }
+
+    #[test]
+    fn changed_or_missing_fields_differ() {
+        let desired = json!({"spec": {"replicas": 1, "paused": false}});
+        assert!(!is_subset(&desired, &json!({"spec": {"replicas": 2, "paused": false}})));
+        assert!(!is_subset(&desired, &json!({"spec": {"replicas": 1}})));
+    }
+
+    #[test]
+    fn null_fields_match_missing_ones() {
+        let desired = json!({"spec": {"replicas": 1, "selector": null}});
+        assert!(is_subset(&desired, &json!({"spec": {"replicas": 1}})));
+    }
+
+    #[test]
+    fn fnv1a_matches_the_reference_values() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn the_desired_hash_ignores_owners_and_itself() {
+        let mut cm = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(String::from("sero-app")),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        set_desired_hash(&mut cm);
+        let hash = cm.annotations()[DESIRED_HASH].clone();
+        set_desired_hash(&mut cm);
+        assert_eq!(cm.annotations()[DESIRED_HASH], hash);
+
+        let mut owned = cm.clone();
+        owned.metadata.owner_references = Some(vec![OwnerReference {
+            name: String::from("app"),
+            uid: String::from("1"),
+            ..Default::default()
+        }]);
+        set_desired_hash(&mut owned);
+        assert_eq!(owned.annotations()[DESIRED_HASH], hash);
+
+        cm.data = Some([(String::from("TIMEOUT"), String::from("2000"))].into());
+        set_desired_hash(&mut cm);
+        assert_ne!(cm.annotations()[DESIRED_HASH], hash);
+    }
+
+    #[test]
+    fn empty_collections_match_missing_ones() {
+        let desired = json!({"spec": {"nodeSelector": {}, "tolerations": [], "replicas": 1}});
+        assert!(is_subset(&desired, &json!({"spec": {"replicas": 1}})));
+        assert!(is_subset(&desired, &json!({"spec": {"replicas": 1, "nodeSelector": null}})));
+        assert!(!is_subset(&json!({"spec": {"tolerations": [{"key": "spot"}]}}), &json!({"spec": {}})));
+    }
+
+    #[test]
+    fn quantities_compare_by_value() {
+        let desired = json!({"resources": {"requests": {"cpu": "0.5", "memory": "1024Mi"}, "limits": {"cpu": "1.5", "memory": "1e9"}}});
+        let actual = json!({"resources": {"requests": {"cpu": "500m", "memory": "1Gi"}, "limits": {"cpu": "1500m", "memory": "1G"}}});
+        assert!(is_subset(&desired, &actual));
+        assert!(!is_subset(&desired, &json!({"resources": {"requests": {"cpu": "600m", "memory": "1Gi"}, "limits": {"cpu": "1500m", "memory": "1G"}}})));
+        // only resources are quantities
+        assert!(!is_subset(&json!({"env": {"value": "0.5"}}), &json!({"env": {"value": "500m"}})));
+    }
+
+    #[test]
+    fn quantities_parse() {
+        assert_eq!(quantity("500m"), Some(500_000_000));
+        assert_eq!(quantity("0.5"), quantity("500m"));
+        assert_eq!(quantity("1Ki"), Some(1024 * 1_000_000_000));
+        assert_eq!(quantity("1.5Gi"), quantity("1536Mi"));
+        assert_eq!(quantity("2e3"), quantity("2k"));
+        assert_eq!(quantity("-1"), Some(-1_000_000_000));
+        assert_eq!(quantity("10x"), None);
+        assert_eq!(quantity("m"), None);
+    }
+
+    #[test]
+    fn arrays_are_compared_by_position() {
+        let desired = json!({"ports": [{"port": 80}, {"port": 443}]});
+        assert!(is_subset(&desired, &json!({"ports": [{"port": 80, "protocol": "TCP"}, {"port": 443, "protocol": "TCP"}]})));
+        assert!(!is_subset(&desired, &json!({"ports": [{"port": 443}, {"port": 80}]})));
+        assert!(!is_subset(&desired, &json!({"ports": [{"port": 80}, {"port": 443}, {"port": 8080}]})));
+    }
+}
@@ -0,0 +1,526 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Result, Ok};
+use clap::{Parser, Subcommand};
+use k8s_openapi::api::apps::v1::Deployment;
+use k8s_openapi::api::coordination::v1::Lease;
+use k8s_openapi::api::core::v1::{ConfigMap, Service, ServiceAccount};
+use k8s_openapi::api::rbac::v1::{Role, RoleBinding};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use k8s_openapi::chrono::Utc;
+use kube::api::{ListParams, Patch, PatchParams};
+use kube::{Api, Client, CustomResourceExt, Resource, ResourceExt};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use sero_operator::api::annotation::{get_type, AppType, OPERATOR_ANNOTATIONS};
+use sero_operator::cli::key_value;
+use sero_operator::crd::SeroProxy;
+use sero_operator::finalizer;
+use sero_operator::render::{self, Manifests};
+use sero_operator::operator_config::{Settings, Sources, CONFIG_FILE_PREFIX};
+use sero_operator::sero_config::SeroConfig;
+use sero_operator::status::{self, SeroStatus, DEGRADED, DRIFTED, READY};
+use sero_operator::{po_to_cfg, sero_manifests, to_config};
+
+const ANNOTATION_PREFIX: &str = "beta.v1.sero/";
+
+/// Inspects and manages sero instances.
+///
+/// Linked or copied as `kubectl-sero` somewhere on the PATH it runs as `kubectl sero`.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Ctl {
+    #[command(subcommand)]
+    command: CtlCommand,
+    /// namespace, defaults to the one of the current context
+    #[arg(short, long, global = true)]
+    namespace: Option<String>,
+    /// operator config file the defaults are taken from
+    #[arg(short, long, global = true, env = "SERO_OPERATOR_CONFIG")]
+    config: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+    /// Lists sero instances with their readiness
+    List {
+        /// list instances of all namespaces
+        #[arg(short = 'A', long)]
+        all_namespaces: bool,
+    },
+    /// Shows the effective config, generated objects and status of an instance
+    Describe { deployment: String },
+    /// Puts sero in front of a deployment by annotating it
+    Enable {
+        deployment: String,
+        /// service routed to the deployment, defaults to the deployment name
+        #[arg(long)]
+        service: Option<String>,
+        /// further sero annotations without prefix, e.g. --set timeout-forward=3000
+        #[arg(long = "set", value_name = "KEY=VALUE", value_parser = key_value)]
+        set: Vec<(String, String)>,
+    },
+    /// Removes the sero annotations of a deployment
+    Disable { deployment: String },
+    /// Prints the objects the operator generates for an instance
+    ///
+    /// With --file the Deployments, SeroProxies and Services are read from manifests
+    /// instead of the cluster and every instance in them is rendered.
+    Render {
+        /// deployment to render, all instances of the files if left out with --file
+        deployment: Option<String>,
+        /// manifest file to read, `-` for stdin, no cluster is needed then
+        #[arg(short, long)]
+        file: Vec<String>,
+    },
+    /// Checks the operator and the instances for problems
+    Doctor {
+        /// check instances of all namespaces
+        #[arg(short = 'A', long)]
+        all_namespaces: bool,
+    },
+}
+
+/// A sero instance, either from an annotated deployment or a SeroProxy.
+struct Instance {
+    kind: &'static str,
+    name: String,
+    namespace: String,
+    config: Result<SeroConfig>,
+    invalid: Vec<String>,
+    status: Option<SeroStatus>,
+    finalizer: bool,
+}
+
+impl Instance {
+    fn from_deployment(d: &Deployment, settings: &Settings) -> Instance {
+        let namespace = d.namespace().unwrap_or_default();
+        let (config, invalid) = match po_to_cfg(d.clone(), namespace.clone(), settings.default_config.clone()) {
+            Result::Ok((c, i)) => (Ok(c), i),
+            Err(e) => (Err(e), vec![]),
+        };
+        Instance {
+            kind: "Deployment",
+            name: d.name_any(),
+            namespace,
+            config,
+            invalid,
+            status: status::from_annotations(Some(d.annotations())),
+            finalizer: finalizer::has_finalizer(d),
+        }
+    }
+
+    fn from_proxy(p: &SeroProxy, settings: &Settings) -> Instance {
+        let namespace = p.namespace().unwrap_or_default();
+        Instance {
+            kind: "SeroProxy",
+            name: p.name_any(),
+            config: p.spec.to_config(namespace.clone(), settings.default_config.clone()),
+            namespace,
+            invalid: vec![],
+            status: p.status.clone(),
+            finalizer: finalizer::has_finalizer(p),
+        }
+    }
+
+    fn deployment(&self) -> String {
+        match &self.config {
+            Result::Ok(c) => c.deployment.clone(),
+            Err(_) => self.name.clone(),
+        }
+    }
+
+    fn ready(&self) -> &'static str {
+        match &self.status {
+            Some(s) if s.is_true(READY) => "True",
+            Some(_) => "False",
+            None => "Unknown",
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::WARN)
+        .with_writer(std::io::stderr)
+        .init();
+
+    let ctl = Ctl::parse();
+    let settings = Settings::load(&Sources {
+        file: ctl.config.clone().unwrap_or_else(|| String::from(CONFIG_FILE_PREFIX)),
+        file_required: ctl.config.is_some(),
+        overrides: vec![],
+    })?;
+    if let CtlCommand::Render { deployment, file } = &ctl.command {
+        if !file.is_empty() {
+            return render_files(file, deployment.as_deref(), ctl.namespace.as_deref().unwrap_or("default"), &settings);
+        }
+    }
+    let client = Client::try_default().await?;
+    let namespace = ctl.namespace.clone().unwrap_or_else(|| client.default_namespace().to_string());
+
+    match ctl.command {
+        CtlCommand::List { all_namespaces } => {
+            let instances = instances(&client, scope(&namespace, all_namespaces), &settings).await?;
+            list(&instances);
+        },
+        CtlCommand::Describe { deployment } => {
+            let instance = find(&client, &namespace, &deployment, &settings).await?;
+            describe(&client, &instance).await?;
+        },
+        CtlCommand::Enable { deployment, service, set } => enable(&client, &namespace, &deployment, service, set, &settings).await?,
+        CtlCommand::Disable { deployment } => disable(&client, &namespace, &deployment).await?,
+        CtlCommand::Render { deployment, .. } => {
+            let deployment = deployment.ok_or_else(|| anyhow!("a deployment or --file is needed"))?;
+            let instance = find(&client, &namespace, &deployment, &settings).await?;
+            render(&client, &instance).await?;
+        },
+        CtlCommand::Doctor { all_namespaces } => {
+            if !doctor(&client, scope(&namespace, all_namespaces), &settings).await? {
+                std::process::exit(1);
+            }
+        },
+    }
+    Ok(())
+}
+
+fn scope(namespace: &str, all_namespaces: bool) -> Option<&str> {
+    match all_namespaces {
+        true => None,
+        false => Some(namespace),
+    }
+}
+
+fn api<K>(client: &Client, namespace: Option<&str>) -> Api<K>
+where
+    K: Resource<Scope = k8s_openapi::NamespaceResourceScope>,
+    K::DynamicType: Default,
+{
+    match namespace {
+        Some(ns) => Api::namespaced(client.clone(), ns),
+        None => Api::all(client.clone()),
+    }
+}
+
+/// All instances of a namespace, or of the cluster without one.
+async fn instances(client: &Client, namespace: Option<&str>, settings: &Settings) -> Result<Vec<Instance>> {
+    let mut instances: Vec<Instance> = api::<Deployment>(client, namespace)
+        .list(&ListParams::default()).await?
+        .iter()
+        .filter(|d| matches!(get_type(d.annotations()), AppType::Managed))
+        .map(|d| Instance::from_deployment(d, settings))
+        .collect();
+    // the crd is optional, annotated deployments work without it
+    match api::<SeroProxy>(client, namespace).list(&ListParams::default()).await {
+        Result::Ok(l) => instances.extend(l.iter().map(|p| Instance::from_proxy(p, settings))),
+        Err(kube::Error::Api(e)) if e.code == 404 => {},
+        Err(e) => return Err(e.into()),
+    }
+    instances.sort_by(|a, b| (&a.namespace, &a.name).cmp(&(&b.namespace, &b.name)));
+    Ok(instances)
+}
+
+/// The instance in front of a deployment.
+async fn find(client: &Client, namespace: &str, deployment: &str, settings: &Settings) -> Result<Instance> {
+    instances(client, Some(namespace), settings).await?
+        .into_iter()
+        .find(|i| i.deployment() == deployment || (i.kind == "SeroProxy" && i.name == deployment))
+        .ok_or_else(|| anyhow!("no sero instance for deployment {}/{}", namespace, deployment))
+}
+
+fn list(instances: &[Instance]) {
+    let rows: Vec<[String; 6]> = instances.iter()
+        .map(|i| {
+            let (service, error) = match &i.config {
+                Result::Ok(c) => (c.service.clone(), i.status.as_ref().and_then(|s| s.last_error.clone()).unwrap_or_default()),
+                Err(e) => (String::new(), e.to_string()),
+            };
+            [i.namespace.clone(), i.deployment(), i.kind.to_string(), service, i.ready().to_string(), error]
+        })
+        .collect();
+    table(["NAMESPACE", "DEPLOYMENT", "SOURCE", "SERVICE", "READY", "ERROR"], rows);
+}
+
+fn table<const N: usize>(header: [&str; N], rows: Vec<[String; N]>) {
+    let mut widths = header.map(|h| h.len());
+    for r in &rows {
+        for (w, c) in widths.iter_mut().zip(r) {
+            *w = (*w).max(c.len());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let cells: Vec<String> = cells.iter().zip(widths).map(|(c, w)| format!("{:w$}", c, w = w)).collect();
+        println!("{}", cells.join("   ").trim_end());
+    };
+    line(header.to_vec());
+    for r in &rows {
+        line(r.iter().map(String::as_str).collect());
+    }
+}
+
+async fn describe(client: &Client, instance: &Instance) -> Result<()> {
+    println!("Name:       {}/{}", instance.namespace, instance.name);
+    println!("Source:     {}", instance.kind);
+    println!("Finalizer:  {}", instance.finalizer);
+    match &instance.config {
+        Result::Ok(c) => {
+            println!("Config:");
+            print!("{}", indent(&serde_yaml::to_string(c)?));
+        },
+        Err(e) => println!("Config:     invalid, {}", e),
+    }
+    if !instance.invalid.is_empty() {
+        println!("Ignored annotations:");
+        for i in &instance.invalid {
+            println!("  {}", i);
+        }
+    }
+    if let Result::Ok(c) = &instance.config {
+        println!("Generated objects:");
+        for (kind, state) in generated(client, c).await? {
+            println!("  {:16} {:24} {}", kind, c.name_patern(), state);
+        }
+        let service: Api<Service> = Api::namespaced(client.clone(), &c.namespace);
+        let state = match service.get_opt(&c.service).await? {
+            Some(_) => "present",
+            None => "missing",
+        };
+        println!("Target service: {} {}", c.service, state);
+    }
+    match &instance.status {
+        Some(s) => {
+            println!("Conditions:");
+            let rows = s.conditions.iter()
+                .map(|c| [c.type_.clone(), c.status.clone(), c.reason.clone(), c.last_transition_time.0.to_rfc3339(), c.message.clone()])
+                .collect();
+            table(["  TYPE", "STATUS", "REASON", "SINCE", "MESSAGE"], rows);
+            if let Some(e) = &s.last_error {
+                println!("Last error: {}", e);
+            }
+        },
+        None => println!("Status:     not reconciled yet"),
+    }
+    Ok(())
+}
+
+fn indent(s: &str) -> String {
+    s.lines().map(|l| format!("  {}\n", l)).collect()
+}
+
+/// Presence of the objects generated for an instance, with the replicas of its deployment.
+async fn generated(client: &Client, config: &SeroConfig) -> Result<Vec<(&'static str, String)>> {
+    let name = config.name_patern();
+    let ns = config.namespace.as_str();
+    let deployment = match Api::<Deployment>::namespaced(client.clone(), ns).get_opt(&name).await? {
+        Some(d) => {
+            let status = d.status.unwrap_or_default();
+            format!("available {}/{}", status.available_replicas.unwrap_or(0), status.replicas.unwrap_or(0))
+        },
+        None => String::from("missing"),
+    };
+    Ok(vec![
+        ("ServiceAccount", present::<ServiceAccount>(client, ns, &name).await?),
+        ("Role", present::<Role>(client, ns, &name).await?),
+        ("RoleBinding", present::<RoleBinding>(client, ns, &name).await?),
+        ("ConfigMap", present::<ConfigMap>(client, ns, &name).await?),
+        ("Service", present::<Service>(client, ns, &name).await?),
+        ("Deployment", deployment),
+    ])
+}
+
+async fn present<K>(client: &Client, namespace: &str, name: &str) -> Result<String>
+where
+    K: Resource<Scope = k8s_openapi::NamespaceResourceScope> + Clone + DeserializeOwned + std::fmt::Debug,
+    K::DynamicType: Default,
+{
+    let found = Api::<K>::namespaced(client.clone(), namespace).get_opt(name).await?.is_some();
+    Ok(String::from(if found { "present" } else { "missing" }))
+}
+
+async fn enable(client: &Client, namespace: &str, deployment: &str, service: Option<String>, set: Vec<(String, String)>, settings: &Settings) -> Result<()> {
+    let api: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    let d = api.get(deployment).await?;
+    if matches!(get_type(d.annotations()), AppType::SeroSelf) {
+        bail!("{} is a sero instance itself", deployment);
+    }
+    let mut annotations = BTreeMap::from([
+        (format!("{}service", ANNOTATION_PREFIX), service.unwrap_or_else(|| deployment.to_string())),
+    ]);
+    for (k, v) in set {
+        annotations.insert(format!("{}{}", ANNOTATION_PREFIX, k.trim_start_matches(ANNOTATION_PREFIX)), v);
+    }
+    // the operator would silently fall back to defaults, so refuse those annotations here
+    let mut merged = d.annotations().clone();
+    merged.extend(annotations.clone());
+    let (_, invalid) = to_config(merged, deployment.to_string(), namespace.to_string(), settings.default_config.clone())?;
+    if !invalid.is_empty() {
+        bail!("invalid sero annotations: {}", invalid.join(", "));
+    }
+    let patch = json!({ "metadata": { "annotations": annotations } });
+    api.patch(deployment, &PatchParams::default(), &Patch::Merge(&patch)).await?;
+    println!("deployment {}/{} enabled", namespace, deployment);
+    Ok(())
+}
+
+async fn disable(client: &Client, namespace: &str, deployment: &str) -> Result<()> {
+    let api: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    let d = api.get(deployment).await?;
+    // the operator removes its own annotations during the teardown
+    let annotations: serde_json::Map<String, Value> = d.annotations().keys()
+        .filter(|k| k.starts_with(ANNOTATION_PREFIX) && !OPERATOR_ANNOTATIONS.contains(&k.as_str()))
+        .map(|k| (k.clone(), Value::Null))
+        .collect();
+    if annotations.is_empty() {
+        let proxies: Api<SeroProxy> = Api::namespaced(client.clone(), namespace);
+        if let Result::Ok(l) = proxies.list(&ListParams::default()).await {
+            if let Some(p) = l.iter().find(|p| p.spec.deployment == deployment) {
+                bail!("{} is managed by seroproxy {}, delete it instead", deployment, p.name_any());
+            }
+        }
+        bail!("{} has no sero annotations", deployment);
+    }
+    let patch = json!({ "metadata": { "annotations": annotations } });
+    api.patch(deployment, &PatchParams::default(), &Patch::Merge(&patch)).await?;
+    println!("deployment {}/{} disabled", namespace, deployment);
+    Ok(())
+}
+
+async fn render(client: &Client, instance: &Instance) -> Result<()> {
+    let config = instance.config.as_ref().map_err(|e| anyhow!("{}", e))?;
+    let target: Api<Service> = Api::namespaced(client.clone(), &config.namespace);
+    let ports = config.proxied_ports(target.get_opt(&config.service).await?.as_ref())?;
+    let owner = match instance.kind {
+        "SeroProxy" => Api::<SeroProxy>::namespaced(client.clone(), &instance.namespace).get(&instance.name).await?.controller_owner_ref(&()),
+        _ => Api::<Deployment>::namespaced(client.clone(), &instance.namespace).get(&instance.name).await?.controller_owner_ref(&()),
+    };
+    print!("{}", sero_manifests(config, owner.as_ref(), &ports).to_yaml()?);
+    Ok(())
+}
+
+fn render_files(files: &[String], deployment: Option<&str>, namespace: &str, settings: &Settings) -> Result<()> {
+    let mut manifests = Manifests::default();
+    for f in files {
+        let yaml = match f.as_str() {
+            "-" => std::io::read_to_string(std::io::stdin())?,
+            _ => std::fs::read_to_string(f).map_err(|e| anyhow!("{}: {}", f, e))?,
+        };
+        manifests.read(&yaml).map_err(|e| anyhow!("{}: {}", f, e))?;
+    }
+    let configs: Vec<_> = manifests.configs(namespace, &settings.default_config)?
+        .into_iter()
+        .filter(|c| deployment.is_none() || deployment == Some(c.deployment.as_str()))
+        .collect();
+    if configs.is_empty() {
+        bail!("no sero instance in {}", files.join(", "));
+    }
+    let docs = configs.iter()
+        .map(|c| render::render(c, &manifests))
+        .collect::<Result<Vec<_>>>()?;
+    print!("{}", docs.join("---\n"));
+    Ok(())
+}
+
+/// Prints a line per check, returns whether everything is fine.
+async fn doctor(client: &Client, namespace: Option<&str>, settings: &Settings) -> Result<bool> {
+    let mut report = Report::default();
+    let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
+    match crds.get_opt(SeroProxy::crd_name()).await {
+        Result::Ok(Some(_)) => report.ok("operator", "SeroProxy crd installed"),
+        Result::Ok(None) => report.warn("operator", "SeroProxy crd not installed, only annotated deployments work"),
+        Err(e) => report.warn("operator", format!("can't read crds: {}", e)),
+    }
+    if settings.leader_election.enabled {
+        leader(client, settings, &mut report).await;
+    }
+
+    for i in instances(client, namespace, settings).await? {
+        let who = format!("{}/{}", i.namespace, i.name);
+        let config = match &i.config {
+            Result::Ok(c) => c,
+            Err(e) => {
+                report.fail(&who, format!("invalid config: {}", e));
+                continue;
+            },
+        };
+        if !i.invalid.is_empty() {
+            report.warn(&who, format!("ignored annotations: {}", i.invalid.join(", ")));
+        }
+        if i.kind == "SeroProxy" && Api::<Deployment>::namespaced(client.clone(), &i.namespace).get_opt(&config.deployment).await?.is_none() {
+            report.fail(&who, format!("target deployment {} does not exist", config.deployment));
+        }
+        if Api::<Service>::namespaced(client.clone(), &i.namespace).get_opt(&config.service).await?.is_none() {
+            report.fail(&who, format!("service {} does not exist", config.service));
+        }
+        for (kind, state) in generated(client, config).await? {
+            match state.as_str() {
+                "missing" => report.fail(&who, format!("{} {} missing", kind, config.name_patern())),
+                s if s.starts_with("available 0/") => report.warn(&who, format!("{} {} has no available replicas", kind, config.name_patern())),
+                _ => {},
+            }
+        }
+        if !i.finalizer {
+            report.warn(&who, "no finalizer, not reconciled yet");
+        }
+        if let Some(c) = i.status.iter().flat_map(|s| &s.conditions).find(|c| c.type_ == DRIFTED && c.status == "True") {
+            report.warn(&who, format!("drifted: {}", c.message));
+        }
+        match &i.status {
+            None => report.warn(&who, "no status, is the namespace watched?"),
+            Some(s) if s.is_true(DEGRADED) => report.fail(&who, format!("degraded: {}", s.last_error.clone().unwrap_or_default())),
+            Some(s) if !s.is_true(READY) => report.warn(&who, "not ready"),
+            Some(_) => report.ok(&who, "ready"),
+        }
+    }
+    Ok(report.healthy)
+}
+
+async fn leader(client: &Client, settings: &Settings, report: &mut Report) {
+    let leases: Api<Lease> = Api::all(client.clone());
+    let lp = ListParams::default().fields(&format!("metadata.name={}", settings.leader_election.lease_name));
+    let lease = match leases.list(&lp).await {
+        Result::Ok(l) => l.items.into_iter().next(),
+        Err(e) => return report.warn("operator", format!("can't read leases: {}", e)),
+    };
+    let spec = match lease.and_then(|l| l.spec) {
+        Some(s) => s,
+        None => return report.fail("operator", format!("lease {} not found, is the operator running?", settings.leader_election.lease_name)),
+    };
+    let holder = spec.holder_identity.unwrap_or_default();
+    let renewed = spec.renew_time.map(|t| Utc::now() - t.0);
+    let duration = spec.lease_duration_seconds.unwrap_or(settings.leader_election.lease_duration_s as i32);
+    match renewed {
+        Some(age) if !holder.is_empty() && age.num_seconds() <= duration as i64 => report.ok("operator", format!("leader {}", holder)),
+        _ => report.fail("operator", format!("lease {} expired, no replica leads", settings.leader_election.lease_name)),
+    }
+}
+
+struct Report {
+    healthy: bool,
+}
+
+impl Default for Report {
+    fn default() -> Report {
+        Report { healthy: true }
+    }
+}
+
+impl Report {
+    fn ok(&mut self, who: &str, message: impl Into<String>) {
+        self.line("OK", who, message.into());
+    }
+
+    fn warn(&mut self, who: &str, message: impl Into<String>) {
+        self.line("WARN", who, message.into());
+    }
+
+    fn fail(&mut self, who: &str, message: impl Into<String>) {
+        self.line("FAIL", who, message.into());
+        self.healthy = false;
+    }
+
+    fn line(&self, level: &str, who: &str, message: String) {
+        println!("{:4}  {:32} {}", level, who, message);
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use k8s_openapi::api::admissionregistration::v1::{MutatingWebhookConfiguration, ValidatingWebhookConfiguration};
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::chrono::{self, DateTime, Datelike, Utc};
+use k8s_openapi::ByteString;
+use kube::api::PostParams;
+use kube::core::ObjectMeta;
+use kube::{Api, Client, ResourceExt};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::operator_config::WebhookSettings;
+
+const CA_CERT: &str = "ca.crt";
+const CA_KEY: &str = "ca.key";
+const TLS_CERT: &str = "tls.crt";
+const TLS_KEY: &str = "tls.key";
+const CA_NOT_AFTER: &str = "sero.rs/ca-not-after";
+const CERT_NOT_AFTER: &str = "sero.rs/cert-not-after";
+const PREVIOUS_CA_CERT: &str = "previous-ca.crt";
+const PREVIOUS_CA_UNTIL: &str = "sero.rs/previous-ca-until";
+
+// how often the secret is checked for a rotation or a certificate rotated by another replica
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+// the ca outlives many serving certificates, so most rotations don't touch the caBundle
+const CA_VALIDITY_FACTOR: i32 = 10;
+// every replica checks the secret within this, so by then none serves a certificate of the previous ca
+const PREVIOUS_CA_TRUST: Duration = Duration::from_secs(2 * CHECK_INTERVAL.as_secs());
+
+/// Self-signed CA and the serving certificate of the webhook.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Certs {
+    pub ca_cert: String,
+    pub ca_key: String,
+    pub cert: String,
+    pub key: String,
+    pub ca_not_after: DateTime<Utc>,
+    pub cert_not_after: DateTime<Utc>,
+    /// CA replaced by the last rotation, trusted next to the new one until `previous_ca_until`
+    pub previous_ca_cert: Option<String>,
+    pub previous_ca_until: Option<DateTime<Utc>>,
+}
+
+impl Certs {
+    /// Creates a new CA and a serving certificate signed by it.
+    pub fn generate(dns_names: &[String], validity: chrono::Duration, now: DateTime<Utc>) -> Result<Certs> {
+        let ca_not_after = now + validity * CA_VALIDITY_FACTOR;
+        let mut params = CertificateParams::default();
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, "sero-operator-ca");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        valid(&mut params, now, ca_not_after);
+        let ca = Certificate::from_params(params)?;
+        let (cert, key, cert_not_after) = serving(&ca, dns_names, validity, now)?;
+        Ok(Certs {
+            ca_cert: ca.serialize_pem()?,
+            ca_key: ca.serialize_private_key_pem(),
+            cert,
+            key,
+            ca_not_after,
+            cert_not_after,
+            previous_ca_cert: None,
+            previous_ca_until: None,
+        })
+    }
+
+    /// Creates a new serving certificate signed by the existing CA.
+    pub fn renew(&self, dns_names: &[String], validity: chrono::Duration, now: DateTime<Utc>) -> Result<Certs> {
+        let params = CertificateParams::from_ca_cert_pem(&self.ca_cert, KeyPair::from_pem(&self.ca_key)?)?;
+        let ca = Certificate::from_params(params)?;
+        let (cert, key, cert_not_after) = serving(&ca, dns_names, validity, now)?;
+        Ok(Certs {
+            cert,
+            key,
+            cert_not_after,
+            ..self.clone()
+        })
+    }
+
+    /// Returns the next certificates if a rotation is due, otherwise `None`.
+    pub fn rotate(&self, dns_names: &[String], validity: chrono::Duration, before: chrono::Duration, now: DateTime<Utc>) -> Result<Option<Certs>> {
+        if self.ca_not_after - before < now {
+            info!("webhook ca expires at {}, creating a new one", self.ca_not_after);
+            // replicas still serve certificates of the old ca until they reload
+            return Ok(Some(Certs {
+                previous_ca_cert: Some(self.ca_cert.clone()),
+                previous_ca_until: Some(now + chrono::Duration::from_std(PREVIOUS_CA_TRUST)?),
+                ..Certs::generate(dns_names, validity, now)?
+            }));
+        }
+        if self.cert_not_after - before < now {
+            info!("webhook certificate expires at {}, renewing it", self.cert_not_after);
+            return self.renew(dns_names, validity, now).map(Some);
+        }
+        if matches!(self.previous_ca_until, Some(until) if until < now) {
+            info!("dropping the previous webhook ca from the caBundle");
+            return Ok(Some(Certs {
+                previous_ca_cert: None,
+                previous_ca_until: None,
+                ..self.clone()
+            }));
+        }
+        Ok(None)
+    }
+
+    /// The CAs the webhook configurations trust, the previous one as well right after a CA rotation.
+    pub fn ca_bundle(&self) -> String {
+        match &self.previous_ca_cert {
+            Some(previous) => format!("{}{}", self.ca_cert, previous),
+            None => self.ca_cert.clone(),
+        }
+    }
+
+    pub fn from_secret(secret: &Secret) -> Option<Certs> {
+        let data = secret.data.as_ref()?;
+        let field = |k: &str| data.get(k).and_then(|v| String::from_utf8(v.0.clone()).ok());
+        let time = |k: &str| secret.annotations().get(k)
+            .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+            .map(|v| v.with_timezone(&Utc));
+        Some(Certs {
+            ca_cert: field(CA_CERT)?,
+            ca_key: field(CA_KEY)?,
+            cert: field(TLS_CERT)?,
+            key: field(TLS_KEY)?,
+            ca_not_after: time(CA_NOT_AFTER)?,
+            cert_not_after: time(CERT_NOT_AFTER)?,
+            previous_ca_cert: field(PREVIOUS_CA_CERT),
+            previous_ca_until: time(PREVIOUS_CA_UNTIL),
+        })
+    }
+
+    pub fn to_secret(&self, name: &str, resource_version: Option<String>) -> Secret {
+        let data = [(CA_CERT, &self.ca_cert), (CA_KEY, &self.ca_key), (TLS_CERT, &self.cert), (TLS_KEY, &self.key)]
+            .into_iter()
+            .chain(self.previous_ca_cert.iter().map(|c| (PREVIOUS_CA_CERT, c)))
+            .map(|(k, v)| (k.to_string(), ByteString(v.clone().into_bytes())))
+            .collect();
+        let mut annotations = BTreeMap::from([
+            (CA_NOT_AFTER.to_string(), self.ca_not_after.to_rfc3339()),
+            (CERT_NOT_AFTER.to_string(), self.cert_not_after.to_rfc3339()),
+        ]);
+        if let Some(until) = self.previous_ca_until {
+            annotations.insert(PREVIOUS_CA_UNTIL.to_string(), until.to_rfc3339());
+        }
+        Secret {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                resource_version,
+                annotations: Some(annotations),
+                ..Default::default()
+            },
+            type_: Some(String::from("kubernetes.io/tls")),
+            data: Some(data),
+            ..Default::default()
+        }
+    }
+}
+
+fn serving(ca: &Certificate, dns_names: &[String], validity: chrono::Duration, now: DateTime<Utc>) -> Result<(String, String, DateTime<Utc>)> {
+    let not_after = now + validity;
+    let mut params = CertificateParams::new(dns_names.to_vec());
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, dns_names.first().cloned().unwrap_or_default());
+    valid(&mut params, now, not_after);
+    let cert = Certificate::from_params(params)?;
+    Ok((cert.serialize_pem_with_signer(ca)?, cert.serialize_private_key_pem(), not_after))
+}
+
+// certificates are valid for whole days, the rotation works on the exact timestamps
+fn valid(params: &mut CertificateParams, from: DateTime<Utc>, until: DateTime<Utc>) {
+    params.not_before = rcgen::date_time_ymd(from.year(), from.month() as u8, from.day() as u8);
+    params.not_after = rcgen::date_time_ymd(until.year(), until.month() as u8, until.day() as u8);
+}
+
+/// Names the webhook service is reached by.
+pub fn dns_names(service: &str, namespace: &str) -> Vec<String> {
+    vec![
+        format!("{}.{}.svc", service, namespace),
+        format!("{}.{}.svc.cluster.local", service, namespace),
+        format!("{}.{}", service, namespace),
+        service.to_string(),
+    ]
+}
+
+/// Loads the certificates from their secret, creates or rotates them when needed
+/// and makes sure the webhook configurations trust the CA.
+pub async fn ensure(client: &Client, settings: &WebhookSettings) -> Result<Certs> {
+    let api: Api<Secret> = Api::default_namespaced(client.clone());
+    let names = dns_names(&settings.service, client.default_namespace());
+    let validity = chrono::Duration::days(settings.cert_validity_days);
+    let before = chrono::Duration::days(settings.rotate_before_days);
+    let now = Utc::now();
+    let existing = api.get_opt(&settings.cert_secret).await?;
+    let current = existing.as_ref().and_then(Certs::from_secret);
+    let next = match &current {
+        Some(c) => c.rotate(&names, validity, before, now)?,
+        None => {
+            info!("creating webhook certificate in secret {}", settings.cert_secret);
+            Some(Certs::generate(&names, validity, now)?)
+        },
+    };
+    let certs = match (next, existing) {
+        (None, _) => current.ok_or_else(|| anyhow!("no webhook certificate"))?,
+        (Some(next), None) => match api.create(&PostParams::default(), &next.to_secret(&settings.cert_secret, None)).await {
+            Ok(_) => next,
+            // another replica was faster
+            Err(kube::Error::Api(e)) if e.code == 409 => stored(&api, &settings.cert_secret).await?,
+            Err(e) => return Err(e.into()),
+        },
+        (Some(next), Some(s)) => {
+            // the resourceVersion keeps replicas from overwriting each others rotation
+            match api.replace(&settings.cert_secret, &PostParams::default(), &next.to_secret(&settings.cert_secret, s.resource_version())).await {
+                Ok(_) => next,
+                Err(kube::Error::Api(e)) if e.code == 409 => stored(&api, &settings.cert_secret).await?,
+                Err(e) => return Err(e.into()),
+            }
+        },
+    };
+    patch_ca_bundle(Api::<ValidatingWebhookConfiguration>::all(client.clone()), &settings.configuration, &certs.ca_bundle()).await?;
+    patch_ca_bundle(Api::<MutatingWebhookConfiguration>::all(client.clone()), &settings.configuration, &certs.ca_bundle()).await?;
+    Ok(certs)
+}
+
+async fn stored(api: &Api<Secret>, name: &str) -> Result<Certs> {
+    Certs::from_secret(&api.get(name).await?).ok_or_else(|| anyhow!("secret {} holds no webhook certificate", name))
+}
+
+async fn patch_ca_bundle<K>(api: Api<K>, name: &str, ca: &str) -> Result<()>
+where
+    K: Clone + DeserializeOwned + Serialize + Debug,
+{
+    let mut value = match api.get_opt(name).await? {
+        Some(v) => serde_json::to_value(v)?,
+        None => return Ok(()),
+    };
+    let bundle = serde_json::to_value(ByteString(ca.as_bytes().to_vec()))?;
+    let mut changed = false;
+    for w in value["webhooks"].as_array_mut().into_iter().flatten() {
+        if w["clientConfig"]["caBundle"] != bundle {
+            w["clientConfig"]["caBundle"] = bundle.clone();
+            changed = true;
+        }
+    }
+    if !changed {
+        return Ok(());
+    }
+    info!("patching caBundle of webhook configuration {}", name);
+    api.replace(name, &PostParams::default(), &serde_json::from_value(value)?).await?;
+    Ok(())
+}
+
+/// Periodically rotates the certificates and reloads the served one when it changed.
+pub async fn rotate(client: Client, settings: WebhookSettings, tls: RustlsConfig, mut current: Certs) {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        match ensure(&client, &settings).await {
+            Ok(certs) if certs.cert != current.cert => {
+                info!("reloading webhook certificate valid until {}", certs.cert_not_after);
+                if let Err(e) = tls.reload_from_pem(certs.cert.clone().into_bytes(), certs.key.clone().into_bytes()).await {
+                    warn!("webhook certificate {}", e);
+                    continue;
+                }
+                current = certs;
+            },
+            Ok(_) => {},
+            Err(e) => warn!("webhook certificate {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names() -> Vec<String> {
+        dns_names("sero-operator", "sero")
+    }
+
+    fn days(n: i64) -> chrono::Duration {
+        chrono::Duration::days(n)
+    }
+
+    #[test]
+    fn generate_creates_a_ca_and_a_serving_certificate() {
+        let now = Utc::now();
+        let certs = Certs::generate(&names(), days(90), now).unwrap();
+        assert!(certs.ca_cert.starts_with("-----BEGIN CERTIFICATE-----"));
+        assert!(certs.cert.starts_with("-----BEGIN CERTIFICATE-----"));
+        assert!(certs.key.contains("PRIVATE KEY"));
+        assert_ne!(certs.ca_cert, certs.cert);
+        assert_eq!(certs.cert_not_after, now + days(90));
+        assert_eq!(certs.ca_not_after, now + days(900));
+        assert_eq!(certs.ca_bundle(), certs.ca_cert);
+    }
+
+    #[test]
+    fn the_certificate_is_renewed_before_it_expires() {
+        let now = Utc::now();
+        let certs = Certs::generate(&names(), days(90), now).unwrap();
+        let rotate_at = certs.cert_not_after - days(30);
+        assert_eq!(certs.rotate(&names(), days(90), days(30), rotate_at - days(1)).unwrap(), None);
+        let renewed = certs.rotate(&names(), days(90), days(30), rotate_at + days(1)).unwrap().unwrap();
+        assert_ne!(renewed.cert, certs.cert);
+        assert_ne!(renewed.key, certs.key);
+        assert_eq!(renewed.cert_not_after, rotate_at + days(91));
+        assert_eq!((renewed.ca_cert.as_str(), renewed.ca_not_after), (certs.ca_cert.as_str(), certs.ca_not_after));
+        assert_eq!(renewed.previous_ca_cert, None);
+    }
+
+    #[test]
+    fn the_ca_is_replaced_and_trusted_with_the_previous_one_for_a_while() {
+        let now = Utc::now();
+        let certs = Certs::generate(&names(), days(90), now).unwrap();
+        let rotate_at = certs.ca_not_after - days(30) + days(1);
+        let rotated = certs.rotate(&names(), days(90), days(30), rotate_at).unwrap().unwrap();
+        assert_ne!(rotated.ca_cert, certs.ca_cert);
+        assert_eq!(rotated.ca_not_after, rotate_at + days(900));
+        assert_eq!(rotated.previous_ca_cert.as_ref(), Some(&certs.ca_cert));
+        assert_eq!(rotated.ca_bundle(), format!("{}{}", rotated.ca_cert, certs.ca_cert));
+
+        let until = rotated.previous_ca_until.unwrap();
+        assert_eq!(rotated.rotate(&names(), days(90), days(30), until).unwrap(), None);
+        let dropped = rotated.rotate(&names(), days(90), days(30), until + chrono::Duration::seconds(1)).unwrap().unwrap();
+        assert_eq!(dropped.ca_bundle(), rotated.ca_cert);
+        assert_eq!(dropped.cert, rotated.cert);
+    }
+
+    #[test]
+    fn certs_round_trip_through_their_secret() {
+        let now = Utc::now();
+        let certs = Certs::generate(&names(), days(90), now).unwrap();
+        assert_eq!(Certs::from_secret(&certs.to_secret("webhook-certs", None)), Some(certs.clone()));
+        let rotated = certs.rotate(&names(), days(90), days(30), certs.ca_not_after).unwrap().unwrap();
+        assert_eq!(Certs::from_secret(&rotated.to_secret("webhook-certs", None)), Some(rotated));
+        assert_eq!(Certs::from_secret(&Secret::default()), None);
+    }
+}
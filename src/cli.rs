@@ -0,0 +1,74 @@
+use clap::{Parser, Subcommand};
+
+use crate::operator_config::{Sources, CONFIG_FILE_PREFIX};
+
+/// Operator running sero.rs instances in front of annotated deployments.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// config file (yaml, toml or json), layered over the built-in defaults
+    #[arg(short, long, env = "SERO_OPERATOR_CONFIG")]
+    pub config: Option<String>,
+    /// watched namespaces, all annotated ones if empty
+    #[arg(long = "namespace", value_delimiter = ',')]
+    pub namespaces: Option<Vec<String>>,
+    /// default sero image
+    #[arg(long)]
+    pub image: Option<String>,
+    /// port of the http server serving /metrics, /healthz and /readyz
+    #[arg(long)]
+    pub http_port: Option<u16>,
+    #[arg(long)]
+    pub leader_election: Option<bool>,
+    /// record writes instead of doing them: none, server (dryRun=All) or skip
+    #[arg(long, value_name = "MODE")]
+    pub dry_run: Option<String>,
+    /// any setting, e.g. --set defaultConfig.timeout.forward=3000
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = key_value)]
+    pub set: Vec<(String, String)>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Runs the operator (default)
+    Run,
+    /// Prints the SeroProxy CustomResourceDefinition
+    Crd,
+}
+
+impl Cli {
+    /// The config file and the flags on top of it.
+    pub fn sources(&self) -> Sources {
+        let mut overrides = vec![];
+        if let Some(v) = &self.namespaces {
+            overrides.push((String::from("namespaces"), v.join(",")));
+        }
+        if let Some(v) = &self.image {
+            overrides.push((String::from("defaultConfig.image"), v.clone()));
+        }
+        if let Some(v) = self.http_port {
+            overrides.push((String::from("http.port"), v.to_string()));
+        }
+        if let Some(v) = self.leader_election {
+            overrides.push((String::from("leaderElection.enabled"), v.to_string()));
+        }
+        if let Some(v) = &self.dry_run {
+            overrides.push((String::from("apply.dryRun"), v.clone()));
+        }
+        overrides.extend(self.set.iter().cloned());
+        Sources {
+            file: self.config.clone().unwrap_or_else(|| String::from(CONFIG_FILE_PREFIX)),
+            file_required: self.config.is_some(),
+            overrides,
+        }
+    }
+}
+
+pub fn key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((k, v)) => Ok((k.to_string(), v.to_string())),
+        None => Err(format!("expected KEY=VALUE, got {}", s)),
+    }
+}
@@ -0,0 +1,592 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Stream, StreamExt};
+use k8s_openapi::api::apps::v1::Deployment;
+use k8s_openapi::api::core::v1::{ConfigMap, Service};
+use kube::api::ListParams;
+use kube::runtime::controller::{self, Action, Controller};
+use kube::runtime::reflector::{ObjectRef, Store};
+use kube::core::ObjectMeta;
+use kube::{Api, Client, Resource, ResourceExt};
+use serde::de::DeserializeOwned;
+use tokio::sync::{watch, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::api::annotation::{get_type, AnnotationWatcher, AppType};
+use crate::crd::SeroProxy;
+use crate::operator_config::{DefaultSeroConfig, Settings};
+use crate::sero_config::SeroConfig;
+use crate::status::{self, SeroStatus, READY};
+use crate::{apply_sero_instance, finalizer, inject, metrics, po_to_cfg, remove_sero_instance, teardown_sero_instance};
+
+// resync of healthy instances, everything else is driven by watch events
+const RESYNC: Duration = Duration::from_secs(300);
+// a sero instance that isn't ready yet is checked again soon
+const NOT_READY_REQUEUE: Duration = Duration::from_secs(30);
+const ERROR_REQUEUE: Duration = Duration::from_secs(15);
+// the error requeue doubles with every failure in a row up to this
+const MAX_ERROR_REQUEUE: Duration = Duration::from_secs(300);
+// a teardown waiting for the workload to become available again
+const TEARDOWN_REQUEUE: Duration = Duration::from_secs(5);
+// how often namespaces that aren't watched anymore are checked for instances left behind
+const RELEASE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("kube error: {0}")]
+    Kube(#[from] kube::Error),
+    #[error("{0:#}")]
+    Reconcile(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Error {
+        Error::Reconcile(e)
+    }
+}
+
+pub struct Context {
+    pub client: Client,
+    // replaced when the config file changes
+    pub settings: watch::Receiver<Settings>,
+    // whether this replica holds the lease, standby replicas only keep their caches warm
+    pub leader: watch::Receiver<bool>,
+    // failed reconciles in a row per object, the retries back off with them
+    pub failures: Mutex<BTreeMap<String, u32>>,
+}
+
+impl Context {
+    /// The current settings.
+    pub fn settings(&self) -> Settings {
+        self.settings.borrow().clone()
+    }
+
+    fn standby(&self) -> Option<Action> {
+        match *self.leader.borrow() {
+            true => None,
+            false => Some(Action::requeue(Duration::from_secs(self.settings().leader_election.retry_period_s))),
+        }
+    }
+}
+
+/// Fires whenever reloaded settings change the effective config of sero instances.
+fn instances_changed(mut settings: watch::Receiver<Settings>) -> impl Stream<Item = ()> + Send + Sync {
+    let current = settings.borrow_and_update().clone();
+    futures::stream::unfold((settings, current), |(mut settings, previous)| async move {
+        loop {
+            settings.changed().await.ok()?;
+            let current = settings.borrow_and_update().clone();
+            if current.default_config != previous.default_config || current.apply != previous.apply || current.output != previous.output {
+                info!("settings changed, reconciling all instances");
+                return Some(((), (settings, current)));
+            }
+        }
+    })
+}
+
+/// Runs the Deployment and SeroProxy controllers of one namespace until they are aborted.
+///
+/// The namespace is added to `listed` once both controllers completed their initial list,
+/// the SeroProxy controller only runs if its crd is installed.
+pub async fn run(namespace: String, ctx: Arc<Context>, listed: Arc<RwLock<BTreeSet<String>>>) {
+    info!("starting controllers for ns {}", namespace);
+    let client = ctx.client.clone();
+    // generated objects carry this label, so only they are watched as owned objects
+    let owned = ListParams::default().labels("beta.v1.sero/deploy");
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), &namespace);
+    let configmaps: Api<ConfigMap> = Api::namespaced(client.clone(), &namespace);
+    let services: Api<Service> = Api::namespaced(client.clone(), &namespace);
+    let proxies: Api<SeroProxy> = Api::namespaced(client, &namespace);
+    // without the crd the SeroProxy watch would fail forever, so only deployments are handled
+    let proxies_installed = match proxies.list_metadata(&ListParams::default().limit(1)).await {
+        Err(kube::Error::Api(e)) if e.code == 404 => {
+            warn!("SeroProxy crd not installed, skipping the SeroProxy controller for ns {}", namespace);
+            false
+        },
+        _ => true,
+    };
+
+    let deployment_controller = Controller::new(deployments.clone(), ListParams::default())
+        .owns(deployments.clone(), owned.clone())
+        .owns(configmaps.clone(), owned.clone())
+        .owns(services.clone(), owned.clone())
+        .reconcile_all_on(instances_changed(ctx.settings.clone()));
+    let deployment_store = deployment_controller.store();
+    let deployment_controller = deployment_controller
+        .run(|d, ctx| instrumented("deployment", failure_key(d.as_ref()), ctx.clone(), reconcile_deployment(d, ctx)), error_policy, ctx.clone())
+        .for_each(|res| log_result(namespace.clone(), res));
+    let proxy_controller = Controller::new(proxies.clone(), ListParams::default());
+    let proxy_store = proxy_controller.store();
+    let targets = proxy_store.clone();
+    let proxy_controller = proxy_controller
+        .owns(deployments.clone(), owned.clone())
+        .owns(configmaps, owned.clone())
+        .owns(services, owned)
+        .reconcile_all_on(instances_changed(ctx.settings.clone()))
+        // scaling the target deployment changes where its service has to route to
+        .watches(deployments.clone(), ListParams::default(), move |d| {
+            targets.state().into_iter()
+                .filter(|p| p.spec.deployment == d.name_any())
+                .map(|p| ObjectRef::from_obj(p.as_ref()))
+                .collect::<Vec<_>>()
+        })
+        .run(|p, ctx| instrumented("seroproxy", failure_key(p.as_ref()), ctx.clone(), reconcile_proxy(p, ctx)), error_policy, ctx)
+        .for_each(|res| log_result(namespace.clone(), res));
+    let proxy_controller = async {
+        if proxies_installed {
+            proxy_controller.await;
+        }
+    };
+    let initial_list = async {
+        initial_list(&deployments, &deployment_store).await;
+        if proxies_installed {
+            initial_list(&proxies, &proxy_store).await;
+        }
+        info!("controllers for ns {} listed", namespace);
+        listed.write().await.insert(namespace.clone());
+    };
+    tokio::join!(deployment_controller, proxy_controller, initial_list);
+    info!("stopped controllers for ns {}", namespace);
+}
+
+/// Waits until the controller's store got filled by its initial list.
+async fn initial_list<K>(api: &Api<K>, store: &Store<K>)
+where
+    K: Resource + Clone + DeserializeOwned + Debug,
+    K::DynamicType: std::hash::Hash + Eq + Clone,
+{
+    loop {
+        if !store.is_empty() {
+            return;
+        }
+        // the store of a namespace without objects stays empty
+        match api.list_metadata(&ListParams::default().limit(1)).await {
+            Ok(l) if l.items.is_empty() => return,
+            Ok(_) => {},
+            Err(e) => warn!("initial list failed: {}", e),
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn instrumented(kind: &str, key: String, ctx: Arc<Context>, reconcile: impl Future<Output = Result<Action, Error>>) -> Result<Action, Error> {
+    if let Some(action) = ctx.standby() {
+        return Ok(action);
+    }
+    let start = Instant::now();
+    let res = reconcile.await;
+    metrics::reconciled(kind, res.is_ok(), start.elapsed());
+    if res.is_ok() {
+        ctx.failures.lock().unwrap().remove(&key);
+    }
+    res
+}
+
+async fn log_result<K, E1, E2>(namespace: String, res: Result<(ObjectRef<K>, Action), controller::Error<E1, E2>>)
+where
+    K: Resource,
+    E1: std::error::Error,
+    E2: std::error::Error,
+{
+    match res {
+        Ok((o, _)) => debug!("reconciled {}", o),
+        // the watch failed and is restarted with a relist
+        Err(e @ controller::Error::QueueError(_)) => {
+            warn!("watch failed: {}", e);
+            metrics::WATCHER_RESTARTS.with_label_values(&[&namespace]).inc();
+        },
+        Err(e) => warn!("reconcile failed: {}", e),
+    }
+}
+
+async fn reconcile_deployment(d: Arc<Deployment>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let app_type = get_type(d.annotations());
+    match app_type {
+        AppType::SeroSelf => return Ok(Action::await_change()),
+        // instances are always created together with the finalizer, so without it there is nothing to clean up
+        AppType::NotManaged if !finalizer::has_finalizer(d.as_ref()) => return Ok(Action::await_change()),
+        _ => {},
+    }
+    let name = d.name_any();
+    let namespace = d.namespace().unwrap_or_default();
+    let owner = d.controller_owner_ref(&()).ok_or_else(|| anyhow::anyhow!("deployment {} has no uid", name))?;
+    let previous = status::from_annotations(Some(d.annotations()));
+    let settings = ctx.settings();
+    let (config, invalid) = deployment_config(d.as_ref(), namespace.clone(), settings.default_config.clone())?;
+    let deleting = d.metadata.deletion_timestamp.is_some();
+
+    if let (AppType::Managed, false) = (app_type, deleting) {
+        info!("reconciling deployment {}/{}", namespace, name);
+        if let Some(action) = retarget(&ctx, d.as_ref(), &config, &owner.uid, &settings).await? {
+            return Ok(action);
+        }
+        let mut status = SeroStatus::new(d.metadata.generation, previous.clone());
+        let mut instance = None;
+        let recorded = status.applied.clone();
+        let applied = async {
+            finalizer::add(&ctx.client, d.as_ref(), &config, &settings.apply).await?;
+            finalizer::record_original(&ctx.client, &config, &settings.apply).await?;
+            instance = Some(apply_sero_instance(&ctx.client, &config, &owner, &recorded, &settings.apply, &settings.output).await?);
+            inject::route(&ctx.client, &config, &settings.apply).await
+        }.await;
+        let managed = applied.is_ok();
+        status.observe(&ctx.client, &config, &invalid, applied).await;
+        if let Some(instance) = instance {
+            status.drifted(&config, &instance.drifted);
+            status.applied = instance.hashes;
+        }
+        metrics::instance_managed(&namespace, &config.name_patern(), managed);
+        let action = requeue_for(&status);
+        status::patch_deployment(&ctx.client, &name, &namespace, previous, status, &settings.apply).await?;
+        return Ok(action);
+    }
+
+    remove_deployment(d.as_ref(), &ctx, &config).await
+}
+
+/// Tears down the instance of a deployment and releases its finalizer.
+async fn remove_deployment(d: &Deployment, ctx: &Context, config: &SeroConfig) -> Result<Action, Error> {
+    let name = d.name_any();
+    let namespace = d.namespace().unwrap_or_default();
+    let owner = d.controller_owner_ref(&()).ok_or_else(|| anyhow::anyhow!("deployment {} has no uid", name))?;
+    let settings = ctx.settings();
+    let deleting = d.metadata.deletion_timestamp.is_some();
+    info!("removing sero from deployment {}/{}", namespace, name);
+    if status::from_annotations(Some(d.annotations())).is_some() && !deleting {
+        status::clear_deployment(&ctx.client, &name, &namespace, &settings.apply).await?;
+    }
+    // a deleted deployment doesn't need its replicas back
+    if !teardown_sero_instance(&ctx.client, config, &owner.uid, !deleting, &settings.apply, &settings.output).await? {
+        return Ok(Action::requeue(TEARDOWN_REQUEUE));
+    }
+    metrics::instance_managed(&namespace, &config.name_patern(), false);
+    finalizer::remove(&ctx.client, d, &settings.apply).await?;
+    Ok(Action::await_change())
+}
+
+/// Tears down the instance recorded with the finalizer if the object now targets another deployment or service.
+///
+/// Returns the action to requeue with while the old workload isn't available yet.
+async fn retarget<K: Resource>(ctx: &Context, obj: &K, config: &SeroConfig, owner_uid: &str, settings: &Settings) -> Result<Option<Action>, Error> {
+    let old = match finalizer::teardown_config(obj) {
+        Some(old) if (&old.namespace, &old.deployment, &old.service) != (&config.namespace, &config.deployment, &config.service) => old,
+        _ => return Ok(None),
+    };
+    info!("{} moved from deployment {} and service {}, tearing down its old instance", obj.name_any(), old.deployment, old.service);
+    if !teardown_sero_instance(&ctx.client, &old, owner_uid, true, &settings.apply, &settings.output).await? {
+        return Ok(Some(Action::requeue(TEARDOWN_REQUEUE)));
+    }
+    Ok(None)
+}
+
+/// Config of an annotated deployment, while its instance is removed the one recorded with the finalizer.
+fn deployment_config(d: &Deployment, namespace: String, default: DefaultSeroConfig) -> anyhow::Result<(SeroConfig, Vec<String>)> {
+    let removing = d.metadata.deletion_timestamp.is_some() || !matches!(get_type(d.annotations()), AppType::Managed);
+    match finalizer::teardown_config(d) {
+        Some(config) if removing => Ok((config, vec![])),
+        _ => po_to_cfg(d.clone(), namespace, default),
+    }
+}
+
+async fn reconcile_proxy(p: Arc<SeroProxy>, ctx: Arc<Context>) -> Result<Action, Error> {
+    let name = p.name_any();
+    let namespace = p.namespace().unwrap_or_default();
+    let owner = p.controller_owner_ref(&()).ok_or_else(|| anyhow::anyhow!("seroproxy {} has no uid", name))?;
+    let previous = p.status.clone();
+    let mut status = SeroStatus::new(p.metadata.generation, previous.clone());
+    let deleting = p.metadata.deletion_timestamp.is_some();
+    let settings = ctx.settings();
+    let config = match finalizer::teardown_config(p.as_ref()) {
+        Some(config) if deleting => Ok(config),
+        _ => p.spec.to_config(namespace.clone(), settings.default_config.clone()),
+    };
+    let config = match config {
+        Ok(v) => v,
+        Err(e) if !deleting => {
+            warn!("error: {}", e);
+            status.invalid_config(&e);
+            status::patch_proxy(&ctx.client, &name, &namespace, previous, status, &settings.apply).await?;
+            return Ok(Action::await_change());
+        },
+        Err(e) => {
+            warn!("error: {}. Releasing {} without teardown.", e, name);
+            finalizer::remove(&ctx.client, p.as_ref(), &settings.apply).await?;
+            return Ok(Action::await_change());
+        },
+    };
+
+    // a proxy that never got the finalizer never created an instance
+    if deleting && !finalizer::has_finalizer(p.as_ref()) {
+        return Ok(Action::await_change());
+    }
+    if !deleting {
+        if let Some(action) = retarget(&ctx, p.as_ref(), &config, &owner.uid, &settings).await? {
+            return Ok(action);
+        }
+    }
+    let conflict = conflict(&ctx.client, p.as_ref()).await?;
+
+    if let (Some(message), false) = (&conflict, deleting) {
+        warn!("seroproxy {}/{} not applied: {}", namespace, name, message);
+        status.conflict(message.clone());
+        status::patch_proxy(&ctx.client, &name, &namespace, previous, status, &settings.apply).await?;
+        // checked again, the other instance may be gone by then
+        return Ok(Action::requeue(NOT_READY_REQUEUE));
+    }
+
+    if !deleting {
+        info!("reconciling seroproxy {}/{}", namespace, name);
+        let mut instance = None;
+        let recorded = status.applied.clone();
+        let applied = async {
+            finalizer::add(&ctx.client, p.as_ref(), &config, &settings.apply).await?;
+            finalizer::record_original(&ctx.client, &config, &settings.apply).await?;
+            instance = Some(apply_sero_instance(&ctx.client, &config, &owner, &recorded, &settings.apply, &settings.output).await?);
+            inject::route(&ctx.client, &config, &settings.apply).await
+        }.await;
+        let managed = applied.is_ok();
+        status.observe(&ctx.client, &config, &[], applied).await;
+        if let Some(instance) = instance {
+            status.drifted(&config, &instance.drifted);
+            status.applied = instance.hashes;
+        }
+        metrics::instance_managed(&namespace, &config.name_patern(), managed);
+        let action = requeue_for(&status);
+        status::patch_proxy(&ctx.client, &name, &namespace, previous, status, &settings.apply).await?;
+        return Ok(action);
+    }
+
+    remove_proxy(p.as_ref(), &ctx, &config, conflict.is_some()).await
+}
+
+/// Tears down the instance of a SeroProxy and releases its finalizer.
+///
+/// With a `conflict` the target belongs to another instance, so only the own objects are removed.
+async fn remove_proxy(p: &SeroProxy, ctx: &Context, config: &SeroConfig, conflict: bool) -> Result<Action, Error> {
+    let name = p.name_any();
+    let namespace = p.namespace().unwrap_or_default();
+    let owner = p.controller_owner_ref(&()).ok_or_else(|| anyhow::anyhow!("seroproxy {} has no uid", name))?;
+    let settings = ctx.settings();
+    info!("removing seroproxy {}/{}", namespace, name);
+    match conflict {
+        true => remove_sero_instance(&ctx.client, config, &owner.uid, &settings.apply, &settings.output).await?,
+        false => if !teardown_sero_instance(&ctx.client, config, &owner.uid, true, &settings.apply, &settings.output).await? {
+            return Ok(Action::requeue(TEARDOWN_REQUEUE));
+        },
+    }
+    metrics::instance_managed(&namespace, &config.name_patern(), false);
+    finalizer::remove(&ctx.client, p, &settings.apply).await?;
+    Ok(Action::await_change())
+}
+
+/// Tears down the instances left in namespaces no controllers run for anymore and releases their finalizers,
+/// otherwise deleting them would hang.
+///
+/// Only done by the leader once the watched namespaces are known.
+pub async fn release_unwatched(ctx: Arc<Context>, watcher: Arc<RwLock<AnnotationWatcher>>, namespaces_listed: Arc<AtomicBool>) {
+    loop {
+        tokio::time::sleep(RELEASE_INTERVAL).await;
+        if ctx.standby().is_some() || !namespaces_listed.load(Ordering::Relaxed) {
+            continue;
+        }
+        let watched = watcher.read().await.namespaces().await;
+        if let Err(e) = release(&ctx, &watched).await {
+            warn!("releasing instances of unwatched namespaces failed: {}", e);
+        }
+    }
+}
+
+async fn release(ctx: &Context, watched: &BTreeSet<String>) -> Result<(), Error> {
+    let left = |m: &ObjectMeta| {
+        !watched.contains(m.namespace.as_deref().unwrap_or_default())
+            && m.finalizers.iter().flatten().any(|f| f == finalizer::FINALIZER)
+    };
+    let deployments: Api<Deployment> = Api::all(ctx.client.clone());
+    let deployments = deployments.list_metadata(&ListParams::default()).await
+        .inspect_err(|_| metrics::api_error("Deployment", "list"))?;
+    for m in deployments.items.into_iter().map(|d| d.metadata).filter(left) {
+        let api: Api<Deployment> = Api::namespaced(ctx.client.clone(), m.namespace.as_deref().unwrap_or_default());
+        let d = match api.get_opt(m.name.as_deref().unwrap_or_default()).await.inspect_err(|_| metrics::api_error("Deployment", "get"))? {
+            Some(v) => v,
+            None => continue,
+        };
+        let config = match finalizer::teardown_config(&d) {
+            Some(v) => v,
+            None => po_to_cfg(d.clone(), d.namespace().unwrap_or_default(), ctx.settings().default_config)?.0,
+        };
+        if let Err(e) = remove_deployment(&d, ctx, &config).await {
+            warn!("releasing deployment {}/{} failed: {}", d.namespace().unwrap_or_default(), d.name_any(), e);
+        }
+    }
+
+    let proxies: Api<SeroProxy> = Api::all(ctx.client.clone());
+    let proxies = match proxies.list_metadata(&ListParams::default()).await {
+        Ok(v) => v,
+        // without the crd there are no SeroProxies
+        Err(kube::Error::Api(e)) if e.code == 404 => return Ok(()),
+        Err(e) => {
+            metrics::api_error("SeroProxy", "list");
+            return Err(e.into());
+        },
+    };
+    for m in proxies.items.into_iter().map(|p| p.metadata).filter(left) {
+        let api: Api<SeroProxy> = Api::namespaced(ctx.client.clone(), m.namespace.as_deref().unwrap_or_default());
+        let p = match api.get_opt(m.name.as_deref().unwrap_or_default()).await.inspect_err(|_| metrics::api_error("SeroProxy", "get"))? {
+            Some(v) => v,
+            None => continue,
+        };
+        let settings = ctx.settings();
+        let config = match finalizer::teardown_config(&p) {
+            Some(v) => Ok(v),
+            None => p.spec.to_config(p.namespace().unwrap_or_default(), settings.default_config),
+        };
+        let res = match config {
+            Ok(config) => {
+                let conflict = conflict(&ctx.client, &p).await?.is_some();
+                remove_proxy(&p, ctx, &config, conflict).await.map(|_| ())
+            },
+            Err(_) => finalizer::remove(&ctx.client, &p, &settings.apply).await.map_err(Error::from),
+        };
+        if let Err(e) = res {
+            warn!("releasing seroproxy {}/{} failed: {}", p.namespace().unwrap_or_default(), p.name_any(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Why a SeroProxy can't manage its deployment, if it can't.
+///
+/// Annotations on the deployment win over a SeroProxy, among SeroProxies the oldest one wins.
+async fn conflict(client: &Client, p: &SeroProxy) -> Result<Option<String>, Error> {
+    let namespace = p.namespace().unwrap_or_default();
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), &namespace);
+    let deployment = deployments.get_opt(&p.spec.deployment).await
+        .inspect_err(|_| metrics::api_error("Deployment", "get"))?;
+    if let Some(d) = deployment {
+        if matches!(get_type(d.annotations()), AppType::Managed) {
+            return Ok(Some(format!("deployment {} is managed by its beta.v1.sero annotations", p.spec.deployment)));
+        }
+    }
+    let proxies: Api<SeroProxy> = Api::namespaced(client.clone(), &namespace);
+    let older = proxies.list(&ListParams::default()).await
+        .inspect_err(|_| metrics::api_error("SeroProxy", "list"))?
+        .items.into_iter()
+        .filter(|o| o.spec.deployment == p.spec.deployment && o.uid() != p.uid())
+        .find(|o| (o.creation_timestamp(), o.name_any()) < (p.creation_timestamp(), p.name_any()));
+    Ok(older.map(|o| format!("seroproxy {} already targets deployment {}", o.name_any(), p.spec.deployment)))
+}
+
+fn requeue_for(status: &SeroStatus) -> Action {
+    if status.last_error.is_some() {
+        Action::requeue(ERROR_REQUEUE)
+    } else if !status.is_true(READY) {
+        Action::requeue(NOT_READY_REQUEUE)
+    } else {
+        Action::requeue(RESYNC)
+    }
+}
+
+fn failure_key<K: Resource<DynamicType = ()>>(obj: &K) -> String {
+    format!("{}/{}/{}", K::kind(&()), obj.namespace().unwrap_or_default(), obj.name_any())
+}
+
+/// Requeue after the `failures`-th failed reconcile in a row.
+fn error_requeue(failures: u32) -> Duration {
+    ERROR_REQUEUE.saturating_mul(2u32.saturating_pow(failures.saturating_sub(1))).min(MAX_ERROR_REQUEUE)
+}
+
+fn error_policy<K: Resource<DynamicType = ()>>(obj: Arc<K>, error: &Error, ctx: Arc<Context>) -> Action {
+    let mut failures = ctx.failures.lock().unwrap();
+    let failed = failures.entry(failure_key(obj.as_ref())).or_default();
+    *failed += 1;
+    let requeue = error_requeue(*failed);
+    warn!("reconcile of {} failed {} times, retrying in {:?}: {}", obj.name_any(), failed, requeue, error);
+    Action::requeue(requeue)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use k8s_openapi::chrono::Utc;
+    use super::*;
+    use crate::sero_config::SeroConfigBuilder;
+
+    #[test]
+    fn error_requeue_backs_off_up_to_the_cap() {
+        assert_eq!(error_requeue(1), ERROR_REQUEUE);
+        assert_eq!(error_requeue(2), ERROR_REQUEUE * 2);
+        assert_eq!(error_requeue(3), ERROR_REQUEUE * 4);
+        assert_eq!(error_requeue(10), MAX_ERROR_REQUEUE);
+        assert_eq!(error_requeue(u32::MAX), MAX_ERROR_REQUEUE);
+    }
+
+    fn deployment(annotations: BTreeMap<String, String>) -> Deployment {
+        Deployment {
+            metadata: ObjectMeta {
+                name: Some(String::from("app")),
+                namespace: Some(String::from("apps")),
+                annotations: Some(annotations),
+                finalizers: Some(vec![String::from(finalizer::FINALIZER)]),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn removed_annotations_tear_down_the_recorded_config() {
+        let recorded = SeroConfigBuilder::new()
+            .deployment(String::from("app"))
+            .namespace(String::from("apps"))
+            .service(String::from("app-svc"))
+            .build()
+            .unwrap();
+        let d = deployment(BTreeMap::from([
+            (String::from(finalizer::TEARDOWN_CONFIG), serde_json::to_string(&recorded).unwrap()),
+        ]));
+        assert!(matches!(get_type(d.annotations()), AppType::NotManaged));
+        let (config, invalid) = deployment_config(&d, String::from("apps"), Settings::default().default_config).unwrap();
+        assert_eq!(config, recorded);
+        assert!(invalid.is_empty());
+    }
+
+    #[test]
+    fn managed_deployments_use_their_annotations() {
+        let recorded = SeroConfigBuilder::new()
+            .deployment(String::from("app"))
+            .namespace(String::from("apps"))
+            .service(String::from("old-svc"))
+            .build()
+            .unwrap();
+        let d = deployment(BTreeMap::from([
+            (String::from("beta.v1.sero/service"), String::from("new-svc")),
+            (String::from(finalizer::TEARDOWN_CONFIG), serde_json::to_string(&recorded).unwrap()),
+        ]));
+        let (config, _) = deployment_config(&d, String::from("apps"), Settings::default().default_config).unwrap();
+        assert_eq!(config.service, "new-svc");
+    }
+
+    #[test]
+    fn deleted_deployments_tear_down_the_recorded_config() {
+        let recorded = SeroConfigBuilder::new()
+            .deployment(String::from("app"))
+            .namespace(String::from("apps"))
+            .service(String::from("old-svc"))
+            .build()
+            .unwrap();
+        let mut d = deployment(BTreeMap::from([
+            (String::from("beta.v1.sero/service"), String::from("new-svc")),
+            (String::from(finalizer::TEARDOWN_CONFIG), serde_json::to_string(&recorded).unwrap()),
+        ]));
+        d.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        let (config, _) = deployment_config(&d, String::from("apps"), Settings::default().default_config).unwrap();
+        assert_eq!(config.service, "old-svc");
+    }
+}
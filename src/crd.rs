@@ -0,0 +1,80 @@
+use anyhow::Result;
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::operator_config::DefaultSeroConfig;
+use crate::sero_config::{port_selector, DriftPolicy, InjectMode, PodSettings, SeroConfig, SeroConfigBuilder};
+use crate::status::SeroStatus;
+
+/// Puts a sero proxy in front of a deployment.
+///
+/// Typed alternative to the `beta.v1.sero/*` annotations.
+/// Every unset field falls back to the operator's `defaultConfig`.
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[kube(group = "sero.rs", version = "v1beta1", kind = "SeroProxy", namespaced)]
+#[kube(shortname = "sero", status = "SeroStatus")]
+#[kube(printcolumn = r#"{"name":"Deployment", "type":"string", "jsonPath":".spec.deployment"}"#)]
+#[kube(printcolumn = r#"{"name":"Service", "type":"string", "jsonPath":".spec.service"}"#)]
+#[kube(printcolumn = r#"{"name":"Ready", "type":"string", "jsonPath":".status.conditions[?(@.type==\"Ready\")].status"}"#)]
+#[kube(printcolumn = r#"{"name":"Error", "type":"string", "jsonPath":".status.lastError", "priority":1}"#)]
+#[serde(rename_all = "camelCase")]
+pub struct SeroProxySpec {
+    /// name of the deployment scaled by sero
+    pub deployment: String,
+    /// name of the service routing to the deployment (not fqdn), defaults to the deployment name
+    pub service: Option<String>,
+    /// whether sero should inject itself to the proxy
+    pub inject: Option<bool>,
+    /// whether sero or the operator routes the service to sero
+    pub inject_mode: Option<InjectMode>,
+    /// sero image used for the proxy
+    pub image: Option<String>,
+    /// protocol of the proxied service if it has no ports, otherwise picks the ports with it unless `ports` is set
+    pub protocol: Option<String>,
+    /// port of the proxied service if it has no ports, otherwise picks the port with it unless `ports` is set
+    pub port: Option<i64>,
+    /// service ports proxied as `<name or number>[/<protocol>]`, defaults to all
+    pub ports: Option<Vec<String>>,
+    pub timeout: Option<SeroProxyTimeout>,
+    /// resources and scheduling of the sero pods, merged over the operator's defaults
+    pub pod: Option<PodSettings>,
+    /// whether changes to the generated objects are repaired or only reported
+    pub drift: Option<DriftPolicy>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SeroProxyTimeout {
+    /// the time sero is waiting when forwarding in ms
+    pub forward: Option<i64>,
+    /// the time sero is waiting for the service to scale up in ms
+    pub scale_up: Option<i64>,
+    /// the time sero is waiting for requests before scaling down in ms
+    pub scale_down: Option<i64>,
+}
+
+impl SeroProxySpec {
+    pub fn to_config(&self, namespace: String, default: DefaultSeroConfig) -> Result<SeroConfig> {
+        let timeout = self.timeout.clone().unwrap_or_default();
+        let mut builder = SeroConfigBuilder::new()
+            .deployment(self.deployment.clone())
+            .namespace(namespace)
+            .image(self.image.clone().unwrap_or(default.image))
+            .inject(self.inject.unwrap_or(default.inject))
+            .inject_mode(self.inject_mode.unwrap_or(default.inject_mode))
+            .protocol(self.protocol.clone().unwrap_or(default.protocol))
+            .port(self.port.unwrap_or(default.port))
+            // with service ports an explicit port and protocol pick among them
+            .ports(self.ports.clone().or_else(|| port_selector(self.port, self.protocol.as_deref()).map(|s| vec![s])).unwrap_or_default())
+            .timeout_forward(timeout.forward.unwrap_or(default.timeout.forward_ms))
+            .timeout_scale_up(timeout.scale_up.unwrap_or(default.timeout.scale_up_ms))
+            .timeout_scale_down(timeout.scale_down.unwrap_or(default.timeout.scale_down_ms))
+            .pod(default.pod.merge(self.pod.clone().unwrap_or_default()))
+            .drift(self.drift.unwrap_or(default.drift));
+        if let Some(service) = self.service.clone() {
+            builder = builder.service(service);
+        }
+        builder.build()
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use k8s_openapi::api::apps::v1::Deployment;
+use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::NamespaceResourceScope;
+use kube::api::Patch;
+use kube::{Api, Client, Resource, ResourceExt};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::apply::patch_params;
+use crate::metrics;
+use crate::operator_config::{ApplySettings, DryRun};
+use crate::sero_config::SeroConfig;
+
+pub const FINALIZER: &str = "sero.rs/teardown";
+
+pub const ORIGINAL_SELECTOR: &str = "beta.v1.sero/original-selector";
+pub const ORIGINAL_REPLICAS: &str = "beta.v1.sero/original-replicas";
+// config the instance was created with, the user may have removed its annotations by the time it's torn down
+pub const TEARDOWN_CONFIG: &str = "beta.v1.sero/teardown-config";
+
+pub fn has_finalizer<K: Resource>(obj: &K) -> bool {
+    obj.finalizers().iter().any(|f| f == FINALIZER)
+}
+
+/// The config recorded by `add`, what the instance has to be torn down with.
+pub fn teardown_config<K: Resource>(obj: &K) -> Option<SeroConfig> {
+    obj.annotations().get(TEARDOWN_CONFIG)
+        .and_then(|c| serde_json::from_str(c).ok())
+}
+
+/// Adds the finalizer and records the config of the instance next to it.
+pub async fn add<K>(client: &Client, obj: &K, sero_config: &SeroConfig, apply: &ApplySettings) -> Result<()>
+where
+    K: Resource<Scope = NamespaceResourceScope> + Clone + DeserializeOwned + std::fmt::Debug,
+    <K as Resource>::DynamicType: Default,
+{
+    let recorded = serde_json::to_string(sero_config)?;
+    if has_finalizer(obj) && obj.annotations().get(TEARDOWN_CONFIG) == Some(&recorded) {
+        return Ok(());
+    }
+    let mut finalizers = obj.finalizers().to_vec();
+    if !has_finalizer(obj) {
+        finalizers.push(FINALIZER.to_string());
+    }
+    let api: Api<K> = Api::namespaced(client.clone(), &obj.namespace().unwrap_or_default());
+    patch_finalizers(&api, obj, finalizers, Value::String(recorded), apply).await
+}
+
+pub async fn remove<K>(client: &Client, obj: &K, apply: &ApplySettings) -> Result<()>
+where
+    K: Resource<Scope = NamespaceResourceScope> + Clone + DeserializeOwned + std::fmt::Debug,
+    <K as Resource>::DynamicType: Default,
+{
+    let api: Api<K> = Api::namespaced(client.clone(), &obj.namespace().unwrap_or_default());
+    // teardown patches the object, so the finalizers are removed from its latest version
+    let kind = K::kind(&Default::default()).to_string();
+    let obj = match api.get_opt(&obj.name_any()).await.inspect_err(|_| metrics::api_error(&kind, "get"))? {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    if !has_finalizer(&obj) {
+        return Ok(());
+    }
+    let finalizers = obj.finalizers().iter()
+        .filter(|f| f.as_str() != FINALIZER)
+        .cloned()
+        .collect();
+    patch_finalizers(&api, &obj, finalizers, Value::Null, apply).await
+}
+
+async fn patch_finalizers<K>(api: &Api<K>, obj: &K, finalizers: Vec<String>, teardown_config: Value, apply: &ApplySettings) -> Result<()>
+where
+    K: Resource + Clone + DeserializeOwned + std::fmt::Debug,
+    <K as Resource>::DynamicType: Default,
+{
+    // the resourceVersion makes the patch fail instead of dropping a concurrently added finalizer
+    let patch = json!({
+        "metadata": {
+            "finalizers": finalizers,
+            "resourceVersion": obj.resource_version(),
+            "annotations": {TEARDOWN_CONFIG: teardown_config},
+        }
+    });
+    let kind = K::kind(&Default::default()).to_string();
+    if let Some(params) = patch_params(apply, "patch", &kind, &obj.namespace().unwrap_or_default(), &obj.name_any()) {
+        api.patch(&obj.name_any(), &params, &Patch::Merge(&patch)).await.inspect_err(|_| metrics::api_error(&kind, "patch"))?;
+    }
+    Ok(())
+}
+
+/// Remembers the selector of the target service and the replicas of the target deployment,
+/// so they can be restored when the sero instance is removed.
+///
+/// Both are recorded again whenever they change, the selector only while it doesn't point at sero
+/// and the replicas only while the workload isn't scaled to zero.
+pub async fn record_original(client: &Client, sero_config: &SeroConfig, apply: &ApplySettings) -> Result<()> {
+    let svc: Api<Service> = Api::namespaced(client.clone(), &sero_config.namespace);
+    if let Some(s) = svc.get_opt(&sero_config.service).await.inspect_err(|_| metrics::api_error("Service", "get"))? {
+        let selector = s.spec.and_then(|s| s.selector).unwrap_or_default();
+        let recorded = serde_json::to_string(&selector)?;
+        if !routed_to_sero(&selector) && s.metadata.annotations.unwrap_or_default().get(ORIGINAL_SELECTOR) != Some(&recorded) {
+            let patch = json!({
+                "metadata": {"annotations": {ORIGINAL_SELECTOR: recorded}}
+            });
+            if let Some(params) = patch_params(apply, "patch", "Service", &sero_config.namespace, &sero_config.service) {
+                svc.patch(&sero_config.service, &params, &Patch::Merge(&patch)).await
+                    .inspect_err(|_| metrics::api_error("Service", "patch"))?;
+            }
+        }
+    }
+
+    let deploy: Api<Deployment> = Api::namespaced(client.clone(), &sero_config.namespace);
+    if let Some(d) = deploy.get_opt(&sero_config.deployment).await.inspect_err(|_| metrics::api_error("Deployment", "get"))? {
+        let replicas = d.spec.and_then(|s| s.replicas).unwrap_or(1);
+        let recorded = replicas.to_string();
+        if replicas > 0 && d.metadata.annotations.unwrap_or_default().get(ORIGINAL_REPLICAS) != Some(&recorded) {
+            let patch = json!({
+                "metadata": {"annotations": {ORIGINAL_REPLICAS: recorded}}
+            });
+            if let Some(params) = patch_params(apply, "patch", "Deployment", &sero_config.namespace, &sero_config.deployment) {
+                deploy.patch(&sero_config.deployment, &params, &Patch::Merge(&patch)).await
+                    .inspect_err(|_| metrics::api_error("Deployment", "patch"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether a service selector points at sero pods.
+pub fn routed_to_sero(selector: &BTreeMap<String, String>) -> bool {
+    selector.contains_key("beta.v1.sero/deploy")
+}
+
+/// Merge patch replacing the `current` selector with `desired`.
+pub fn selector_patch(current: BTreeMap<String, String>, desired: BTreeMap<String, String>) -> Value {
+    // a merge patch only adds keys, so every key not in the desired selector is nulled
+    let mut selector: BTreeMap<String, Value> = current
+        .into_keys()
+        .map(|k| (k, Value::Null))
+        .collect();
+    selector.extend(desired.into_iter().map(|(k, v)| (k, Value::String(v))));
+    json!(selector)
+}
+
+/// Scales the workload back up if sero left it at zero, returns whether it can take the traffic again.
+///
+/// While the target service still routes to sero that needs an available replica,
+/// otherwise sero would be removed while nothing serves the service.
+pub async fn restore_replicas(client: &Client, sero_config: &SeroConfig, scale: bool, apply: &ApplySettings) -> Result<bool> {
+    let deploy: Api<Deployment> = Api::namespaced(client.clone(), &sero_config.namespace);
+    let d = match deploy.get_opt(&sero_config.deployment).await.inspect_err(|_| metrics::api_error("Deployment", "get"))? {
+        Some(v) => v,
+        None => return Ok(true),
+    };
+    let annotations = d.metadata.annotations.clone().unwrap_or_default();
+    let original = annotations.get(ORIGINAL_REPLICAS)
+        .and_then(|v| v.parse::<i32>().ok())
+        .unwrap_or(1);
+    let replicas = d.spec.as_ref().and_then(|s| s.replicas).unwrap_or(1);
+    let mut patch = json!({
+        "metadata": {"annotations": {ORIGINAL_REPLICAS: null}},
+    });
+    let scaling = scale && replicas == 0;
+    if scaling {
+        info!("scaling deployment {} back to {}", sero_config.deployment, original);
+        patch["spec"] = json!({"replicas": original});
+    }
+    if scaling || annotations.contains_key(ORIGINAL_REPLICAS) {
+        if let Some(params) = patch_params(apply, "patch", "Deployment", &sero_config.namespace, &sero_config.deployment) {
+            deploy.patch(&sero_config.deployment, &params, &Patch::Merge(&patch)).await
+                .inspect_err(|_| metrics::api_error("Deployment", "patch"))?;
+        }
+    }
+    // a deleted workload doesn't come back and nothing scales in a dry run
+    if !scale || apply.dry_run != DryRun::None {
+        return Ok(true);
+    }
+    let svc: Api<Service> = Api::namespaced(client.clone(), &sero_config.namespace);
+    let routed = svc.get_opt(&sero_config.service).await.inspect_err(|_| metrics::api_error("Service", "get"))?
+        .and_then(|s| s.spec)
+        .and_then(|s| s.selector)
+        .map(|s| routed_to_sero(&s))
+        .unwrap_or(false);
+    let available = d.status.and_then(|s| s.available_replicas).unwrap_or(0);
+    if routed && (scaling || available == 0) {
+        info!("waiting for deployment {} to become available", sero_config.deployment);
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Points the target service back at the workload.
+pub async fn restore_selector(client: &Client, sero_config: &SeroConfig, apply: &ApplySettings) -> Result<()> {
+    let svc: Api<Service> = Api::namespaced(client.clone(), &sero_config.namespace);
+    if let Some(s) = svc.get_opt(&sero_config.service).await.inspect_err(|_| metrics::api_error("Service", "get"))? {
+        let original = s.metadata.annotations.unwrap_or_default().get(ORIGINAL_SELECTOR)
+            .map(|v| serde_json::from_str::<BTreeMap<String, String>>(v))
+            .transpose()?;
+        if let Some(original) = original {
+            let current = s.spec.and_then(|s| s.selector).unwrap_or_default();
+            let mut patch = json!({
+                "metadata": {"annotations": {ORIGINAL_SELECTOR: null}},
+            });
+            // a selector that doesn't point at sero is newer than the recorded one
+            if routed_to_sero(&current) {
+                info!("restoring selector of service {}", sero_config.service);
+                patch["spec"] = json!({"selector": selector_patch(current, original)});
+            }
+            if let Some(params) = patch_params(apply, "patch", "Service", &sero_config.namespace, &sero_config.service) {
+                svc.patch(&sero_config.service, &params, &Patch::Merge(&patch)).await
+                    .inspect_err(|_| metrics::api_error("Service", "patch"))?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use k8s_openapi::api::apps::v1::Deployment;
+use k8s_openapi::api::core::v1::Service;
+use kube::api::Patch;
+use kube::{Api, Client, ResourceExt};
+use serde_json::json;
+use tracing::info;
+
+use crate::apply::patch_params;
+use crate::metrics;
+use crate::finalizer::{routed_to_sero, selector_patch, ORIGINAL_SELECTOR};
+use crate::operator_config::ApplySettings;
+use crate::sero_config::SeroConfig;
+
+/// Labels of the sero pods of an instance.
+pub fn sero_selector(sero_config: &SeroConfig) -> BTreeMap<String, String> {
+    BTreeMap::from([
+        (String::from("beta.v1.sero/deploy"), sero_config.deployment.clone()),
+        (String::from("beta.v1.sero/service"), sero_config.service.clone()),
+    ])
+}
+
+/// Routes the target service to sero while the workload is scaled to zero
+/// and back to the workload once it has available replicas again.
+///
+/// Only done in the `operator` inject mode, the original selector is recorded by `finalizer::record_original`.
+pub async fn route(client: &Client, sero_config: &SeroConfig, apply: &ApplySettings) -> Result<()> {
+    if !sero_config.operator_inject() {
+        return Ok(());
+    }
+    let deploy: Api<Deployment> = Api::namespaced(client.clone(), &sero_config.namespace);
+    let svc: Api<Service> = Api::namespaced(client.clone(), &sero_config.namespace);
+    let d = match deploy.get_opt(&sero_config.deployment).await.inspect_err(|_| metrics::api_error("Deployment", "get"))? {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    let s = match svc.get_opt(&sero_config.service).await.inspect_err(|_| metrics::api_error("Service", "get"))? {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    let recorded = match s.annotations().get(ORIGINAL_SELECTOR) {
+        Some(v) => serde_json::from_str::<BTreeMap<String, String>>(v)?,
+        None => return Ok(()),
+    };
+    let replicas = d.spec.and_then(|s| s.replicas).unwrap_or(1);
+    let available = d.status.and_then(|s| s.available_replicas).unwrap_or(0);
+    let current = s.spec.and_then(|s| s.selector).unwrap_or_default();
+    // the recorded selector is only needed to route back from sero, a current one is newer
+    let original = if routed_to_sero(&current) { recorded } else { current.clone() };
+    // while the workload scales up sero keeps the requests, so the service stays routed to it
+    let desired = if replicas == 0 {
+        sero_selector(sero_config)
+    } else if available > 0 {
+        original
+    } else {
+        return Ok(());
+    };
+    if current == desired {
+        return Ok(());
+    }
+    info!("routing service {} to {:?}", sero_config.service, desired);
+    let patch = json!({"spec": {"selector": selector_patch(current, desired)}});
+    if let Some(params) = patch_params(apply, "patch", "Service", &sero_config.namespace, &sero_config.service) {
+        svc.patch(&sero_config.service, &params, &Patch::Merge(&patch)).await
+            .inspect_err(|_| metrics::api_error("Service", "patch"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use k8s_openapi::chrono::{self, Utc};
+use kube::api::PostParams;
+use kube::core::ObjectMeta;
+use kube::{Api, Client, ResourceExt};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::metrics;
+use crate::operator_config::LeaderElectionSettings;
+
+/// Lease based leader election (coordination.k8s.io/v1).
+///
+/// Every replica runs the elector, only the holder of the lease reconciles.
+pub struct LeaderElector {
+    api: Api<Lease>,
+    identity: String,
+    settings: LeaderElectionSettings,
+}
+
+impl LeaderElector {
+    pub fn new(client: Client, settings: LeaderElectionSettings) -> LeaderElector {
+        let identity = std::env::var("HOSTNAME")
+            .unwrap_or_else(|_| format!("sero-operator-{}", std::process::id()));
+        LeaderElector {
+            api: Api::default_namespaced(client),
+            identity,
+            settings,
+        }
+    }
+
+    /// Tries to acquire or renew the lease every retry period and publishes whether this replica leads.
+    pub async fn run(&self, tx: watch::Sender<bool>) {
+        let renew_deadline = Duration::from_secs(self.settings.renew_deadline_s);
+        let mut last_renew = Instant::now();
+        loop {
+            let leading = *tx.borrow();
+            // a leader has to step down at the deadline, however long the api server takes to answer
+            let timeout = match leading {
+                true => renew_deadline.saturating_sub(last_renew.elapsed()),
+                false => renew_deadline,
+            };
+            let renewed = match tokio::time::timeout(timeout, self.try_acquire_or_renew()).await {
+                Ok(v) => v,
+                Err(_) => Err(anyhow!("renewing timed out after {:?}", timeout)),
+            };
+            match renewed {
+                Ok(true) => {
+                    if !leading {
+                        info!("{} acquired lease {}", self.identity, self.settings.lease_name);
+                    }
+                    last_renew = Instant::now();
+                    tx.send_replace(true);
+                },
+                Ok(false) => {
+                    if leading {
+                        info!("{} lost lease {}", self.identity, self.settings.lease_name);
+                    }
+                    tx.send_replace(false);
+                },
+                Err(e) => warn!("lease {}", e),
+            }
+            if *tx.borrow() && last_renew.elapsed() >= renew_deadline {
+                warn!("{} could not renew lease {} in time", self.identity, self.settings.lease_name);
+                tx.send_replace(false);
+            }
+            if leading && !*tx.borrow() {
+                metrics::reset_instances();
+            }
+            // a leader wakes up at its deadline even if that's before the next retry
+            let mut sleep = Duration::from_secs(self.settings.retry_period_s);
+            if *tx.borrow() {
+                sleep = sleep.min(renew_deadline.saturating_sub(last_renew.elapsed()));
+            }
+            tokio::time::sleep(sleep).await;
+        }
+    }
+
+    async fn try_acquire_or_renew(&self) -> Result<bool> {
+        let now = Utc::now();
+        let name = &self.settings.lease_name;
+        let lease = match self.api.get_opt(name).await? {
+            Some(v) => v,
+            None => {
+                let lease = self.lease(None, now, now, 0);
+                return match self.api.create(&PostParams::default(), &lease).await {
+                    Ok(_) => Ok(true),
+                    // somebody else created it first
+                    Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+                    Err(e) => Err(e.into()),
+                };
+            },
+        };
+        let spec = lease.spec.clone().unwrap_or_default();
+        let holder = spec.holder_identity.filter(|h| !h.is_empty());
+        let held_by_us = holder.as_deref() == Some(self.identity.as_str());
+        let duration = chrono::Duration::seconds(spec.lease_duration_seconds.unwrap_or(0).into());
+        let expired = match spec.renew_time {
+            Some(t) => t.0 + duration < now,
+            None => true,
+        };
+        if !held_by_us && holder.is_some() && !expired {
+            return Ok(false);
+        }
+
+        let transitions = spec.lease_transitions.unwrap_or(0);
+        let new = match held_by_us {
+            true => self.lease(lease.resource_version(), spec.acquire_time.map_or(now, |t| t.0), now, transitions),
+            false => self.lease(lease.resource_version(), now, now, transitions + 1),
+        };
+        // the resourceVersion makes concurrent takeovers conflict instead of both succeeding
+        match self.api.replace(name, &PostParams::default(), &new).await {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Gives up the lease on shutdown, so a standby replica can take over without waiting for it to expire.
+    pub async fn release(&self) -> Result<()> {
+        let lease = match self.api.get_opt(&self.settings.lease_name).await? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let spec = lease.spec.clone().unwrap_or_default();
+        if spec.holder_identity.as_deref() != Some(self.identity.as_str()) {
+            return Ok(());
+        }
+        info!("{} releasing lease {}", self.identity, self.settings.lease_name);
+        let mut released = lease;
+        released.spec = Some(LeaseSpec {
+            holder_identity: None,
+            lease_duration_seconds: Some(1),
+            renew_time: Some(MicroTime(Utc::now())),
+            ..spec
+        });
+        self.api.replace(&self.settings.lease_name, &PostParams::default(), &released).await?;
+        Ok(())
+    }
+
+    fn lease(&self, resource_version: Option<String>, acquired: chrono::DateTime<Utc>, renewed: chrono::DateTime<Utc>, transitions: i32) -> Lease {
+        Lease {
+            metadata: ObjectMeta {
+                name: Some(self.settings.lease_name.clone()),
+                resource_version,
+                ..Default::default()
+            },
+            spec: Some(LeaseSpec {
+                holder_identity: Some(self.identity.clone()),
+                lease_duration_seconds: Some(self.settings.lease_duration_s as i32),
+                acquire_time: Some(MicroTime(acquired)),
+                renew_time: Some(MicroTime(renewed)),
+                lease_transitions: Some(transitions),
+            }),
+        }
+    }
+}
@@ -0,0 +1,550 @@
+pub mod api;
+pub mod apply;
+pub mod certs;
+pub mod cli;
+pub mod controller;
+pub mod crd;
+pub mod finalizer;
+pub mod inject;
+pub mod leader;
+pub mod metrics;
+pub mod operator_config;
+pub mod output;
+pub mod render;
+pub mod sero_config;
+pub mod server;
+pub mod status;
+pub mod webhook;
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{Result, Ok};
+use anyhow::bail;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use kube::core::{ObjectMeta};
+use k8s_openapi::api::apps::v1::{Deployment, DeploymentSpec};
+use k8s_openapi::api::core::v1::{EnvFromSource, Container, ContainerPort, PodSpec, PodTemplateSpec,ConfigMapEnvSource, ConfigMap, Service, ServiceAccount, ServiceSpec, ServicePort};
+use k8s_openapi::api::rbac::v1::{PolicyRule, Role, RoleBinding, RoleRef, Subject};
+use kube::{api::Api, Client};
+use tracing::{info, warn};
+
+use api::annotation;
+use apply::{create_or_update, delete_owned, set_desired_hash, Applied, DESIRED_HASH};
+use operator_config::{DefaultSeroConfig, ApplySettings, OutputMode, OutputSettings};
+use sero_config::{port_selector, DriftPolicy, InjectMode, PodSettings, ProxiedPort, SeroConfigBuilder, SeroConfig, PROTOCOLS};
+
+pub fn po_to_cfg(data: Deployment, namespace: String, default: DefaultSeroConfig) -> Result<(SeroConfig, Vec<String>)> {
+    let annotations = match data.metadata.annotations.clone() {
+        Some(v) => v,
+        None => {bail!("no annotation on new service")},
+    };
+    // deployments that only have a generateName yet can't be targeted
+    let name = match data.metadata.name {
+        Some(v) => v,
+        None => {bail!("deployment has no name")},
+    };
+    to_config(annotations, name, namespace, default)
+}
+
+/// What applying the objects of a sero instance found.
+#[derive(Debug, Default)]
+pub struct AppliedInstance {
+    /// kinds of the objects that drifted from the desired state
+    pub drifted: Vec<String>,
+    /// desired hash of every applied object by kind
+    pub hashes: BTreeMap<String, String>,
+}
+
+impl AppliedInstance {
+    fn add<K: kube::Resource>(&mut self, kind: &str, obj: &K, applied: Applied) {
+        match applied {
+            Applied::Drifted => self.drifted.push(kind.to_string()),
+            Applied::Deleted => self.drifted.push(format!("{} (deleted)", kind)),
+            _ => {},
+        }
+        if let Some(hash) = obj.meta().annotations.as_ref().and_then(|a| a.get(DESIRED_HASH)) {
+            self.hashes.insert(kind.to_string(), hash.clone());
+        }
+    }
+}
+
+/// Creates or updates the objects of a sero instance.
+///
+/// `recorded` holds the desired hashes the objects were last applied with, so deleted objects are told from new ones.
+pub async fn apply_sero_instance(client: &Client, sero_config: &SeroConfig, owner: &OwnerReference, recorded: &BTreeMap<String, String>, apply: &ApplySettings, output: &OutputSettings) -> Result<AppliedInstance> {
+    info!("Creating new Sero instance for deploy {}", sero_config.deployment);
+    let name = sero_config.name_patern();
+    let mut errors = vec![];
+    let target: Api<Service> = Api::namespaced(client.clone(), &sero_config.namespace);
+    let ports = sero_config.proxied_ports(target.get_opt(&sero_config.service).await?.as_ref())?;
+    // the pipeline applies the manifests, the operator doesn't create anything itself
+    if output.mode != OutputMode::Apply {
+        output::write(client, sero_config, &render::document(sero_config, &ports)?, output, apply).await?;
+        return Ok(AppliedInstance::default());
+    }
+    let m = sero_manifests(sero_config, Some(owner), &ports);
+    let repair = sero_config.drift == DriftPolicy::Repair;
+    let mut applied = AppliedInstance::default();
+    match create_or_update(client, &m.service_account, &name, &sero_config.namespace, apply, repair, recorded.get("ServiceAccount")).await {
+        Result::Ok(a) => applied.add("ServiceAccount", &m.service_account, a),
+        Err(e) => {warn!("sa {}", e); errors.push(format!("sa {}", e))},
+    };
+    match create_or_update(client, &m.role, &name, &sero_config.namespace, apply, repair, recorded.get("Role")).await {
+        Result::Ok(a) => applied.add("Role", &m.role, a),
+        Err(e) => {warn!("role {}", e); errors.push(format!("role {}", e))},
+    };
+    match create_or_update(client, &m.role_binding, &name, &sero_config.namespace, apply, repair, recorded.get("RoleBinding")).await {
+        Result::Ok(a) => applied.add("RoleBinding", &m.role_binding, a),
+        Err(e) => {warn!("rolebinding {}", e); errors.push(format!("rolebinding {}", e))},
+    };
+    match create_or_update(client, &m.deployment, &name, &sero_config.namespace, apply, repair, recorded.get("Deployment")).await {
+        Result::Ok(a) => applied.add("Deployment", &m.deployment, a),
+        Err(e) => {warn!("deploy {}", e); errors.push(format!("deploy {}", e))},
+    };
+    match create_or_update(client, &m.config_map, &name, &sero_config.namespace, apply, repair, recorded.get("ConfigMap")).await {
+        Result::Ok(a) => applied.add("ConfigMap", &m.config_map, a),
+        Err(e) => {warn!("cm {}", e); errors.push(format!("cm {}", e))},
+    };
+    match create_or_update(client, &m.service, &name, &sero_config.namespace, apply, repair, recorded.get("Service")).await {
+        Result::Ok(a) => applied.add("Service", &m.service, a),
+        Err(e) => {warn!("svc {}", e); errors.push(format!("svc {}", e))},
+    };
+    if !errors.is_empty() {
+        bail!(errors.join(", "));
+    }
+    Ok(applied)
+}
+
+/// Objects making up a sero instance.
+pub struct SeroManifests {
+    pub service_account: ServiceAccount,
+    pub role: Role,
+    pub role_binding: RoleBinding,
+    pub deployment: Deployment,
+    pub config_map: ConfigMap,
+    pub service: Service,
+}
+
+impl SeroManifests {
+    /// All objects as one multi-document yaml.
+    pub fn to_yaml(&self) -> Result<String> {
+        let docs = [
+            serde_yaml::to_string(&self.service_account)?,
+            serde_yaml::to_string(&self.role)?,
+            serde_yaml::to_string(&self.role_binding)?,
+            serde_yaml::to_string(&self.deployment)?,
+            serde_yaml::to_string(&self.config_map)?,
+            serde_yaml::to_string(&self.service)?,
+        ];
+        Ok(docs.join("---\n"))
+    }
+}
+
+/// Builds the objects of a sero instance, `owner` is left out when rendering without a cluster.
+pub fn sero_manifests(sero_config: &SeroConfig, owner: Option<&OwnerReference>, ports: &[ProxiedPort]) -> SeroManifests {
+    let sero_config_str = serde_json::to_string(&sero_config).unwrap();
+    let some_name = Some(sero_config.name_patern());
+    let some_namespace = Some(sero_config.namespace.clone());
+    let name = sero_config.name_patern();
+    let mut listen = BTreeSet::new();
+
+    let rbac_meta = ObjectMeta {
+        name: some_name.clone(),
+        namespace: some_namespace.clone(),
+        annotations: Some(
+            BTreeMap::from([
+                (String::from("beta.v1.sero/config"), sero_config_str.clone()),
+            ])
+        ),
+        labels: Some(
+            BTreeMap::from([
+                (String::from("beta.v1.sero/deploy"), sero_config.deployment.clone()),
+                (String::from("beta.v1.sero/service"), sero_config.service.clone()),
+            ])
+        ),
+        owner_references: owner.map(|o| vec![o.clone()]),
+        ..Default::default()
+    };
+    let sa = ServiceAccount {
+        metadata: rbac_meta.clone(),
+        ..Default::default()
+    };
+    // sero may only scale its own deployment and route its own service
+    let role = Role {
+        metadata: rbac_meta.clone(),
+        rules: Some(vec![
+            PolicyRule {
+                api_groups: Some(vec![String::from("apps")]),
+                resources: Some(vec![String::from("deployments/scale")]),
+                resource_names: Some(vec![sero_config.deployment.clone()]),
+                verbs: vec![String::from("get"), String::from("patch")],
+                ..Default::default()
+            },
+            PolicyRule {
+                api_groups: Some(vec![String::new()]),
+                resources: Some(vec![String::from("services")]),
+                resource_names: Some(vec![sero_config.service.clone()]),
+                verbs: vec![String::from("get"), String::from("patch")],
+                ..Default::default()
+            },
+        ]),
+    };
+    let binding = RoleBinding {
+        metadata: rbac_meta.clone(),
+        role_ref: RoleRef {
+            api_group: String::from("rbac.authorization.k8s.io"),
+            kind: String::from("Role"),
+            name: name.clone(),
+        },
+        subjects: Some(vec![Subject {
+            kind: String::from("ServiceAccount"),
+            name: name.clone(),
+            namespace: some_namespace.clone(),
+            ..Default::default()
+        }]),
+    };
+
+    let deployment = Deployment {
+        metadata: ObjectMeta {
+            name: some_name.clone(),
+            namespace: some_namespace.clone(),
+            annotations: Some(BTreeMap::from(
+                [("beta.v1.sero/config".to_string(), sero_config_str.clone()),]
+            )),
+            owner_references: owner.map(|o| vec![o.clone()]),
+            ..Default::default() 
+        },
+        spec: Some(DeploymentSpec {
+            selector: LabelSelector {
+                match_labels: Some(
+                    BTreeMap::from([
+                        (String::from("beta.v1.sero/deploy"), sero_config.deployment.clone()),
+                        (String::from("beta.v1.sero/service"), sero_config.service.clone()),
+                    ])),
+                ..Default::default()
+            },
+            template: PodTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    annotations: Some(
+                        BTreeMap::from([
+                            (String::from("beta.v1.sero/config"), sero_config_str.clone()),
+                        ])
+                    ),
+                    labels: Some(
+                        BTreeMap::from([
+                            (String::from("beta.v1.sero/deploy"), sero_config.deployment.clone()),
+                            (String::from("beta.v1.sero/service"), sero_config.service.clone()),
+                        ])
+                    ),
+                    ..Default::default()
+                }),
+                spec: Some(PodSpec {
+                    automount_service_account_token: Some(true),
+                    service_account_name: some_name.clone(),
+                    node_selector: sero_config.pod.node_selector.clone(),
+                    tolerations: sero_config.pod.tolerations.clone(),
+                    affinity: sero_config.pod.affinity.clone(),
+                    priority_class_name: sero_config.pod.priority_class_name.clone(),
+                    topology_spread_constraints: sero_config.pod.topology_spread_constraints.clone(),
+                    containers: vec![Container {
+                        env_from: Some(vec![
+                            EnvFromSource {
+                                config_map_ref: Some(ConfigMapEnvSource {
+                                    name: some_name.clone(),
+                                    optional: Some(false),
+                                }),
+                                ..Default::default()
+                            }
+                        ]),
+                        image: Some(sero_config.image.clone()),
+                        name: String::from("sero"),
+                        resources: sero_config.pod.resources.clone(),
+                        // service ports sharing a named target port listen on the same container port
+                        ports: Some(ports.iter()
+                            .filter(|p| listen.insert(p.listen))
+                            .map(|p| ContainerPort {
+                                container_port: p.listen,
+                                name: p.listen_name.clone(),
+                                protocol: Some(p.protocol.clone()),
+                                ..Default::default()
+                            }).collect()),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }),
+            },
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let configmap = ConfigMap {
+        data: Some(
+            BTreeMap::from([
+                ("deployment".to_uppercase(), sero_config.deployment.clone()),
+                ("service".to_uppercase(), sero_config.service.clone()),
+                // sero must not fight the operator over the service
+                ("inject".to_uppercase(), (sero_config.service_inject && !sero_config.operator_inject()).to_string()),
+                ("protocol".to_uppercase(), ports[0].protocol.clone()),
+                ("port".to_uppercase(), ports[0].port.to_string()),
+                // <listen port>:<service port>/<protocol>
+                ("ports".to_uppercase(), ports.iter().map(|p| format!("{}:{}/{}", p.listen, p.port, p.protocol)).collect::<Vec<_>>().join(",")),
+                ("timeout_forward".to_uppercase(), sero_config.timeout_forward_ms.to_string()),
+                ("timeout_scale_up".to_uppercase(), sero_config.timeout_scale_up_ms.to_string()),
+                ("timeout_scale_down".to_uppercase(), sero_config.timeout_scale_down_ms.to_string()),
+            ]),
+        ),
+        metadata: ObjectMeta {
+            name: some_name.clone(),
+            namespace: some_namespace.clone(),
+            annotations: Some(
+                BTreeMap::from([
+                    (String::from("beta.v1.sero/config"), sero_config_str.clone()),
+                ])
+            ),
+            labels: Some(
+                BTreeMap::from([
+                    (String::from("beta.v1.sero/deploy"), sero_config.deployment.clone()),
+                    (String::from("beta.v1.sero/service"), sero_config.service.clone()),
+                ])
+            ),
+            owner_references: owner.map(|o| vec![o.clone()]),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let svc = Service {
+        metadata: ObjectMeta {
+            name: some_name.clone(),
+            namespace: some_namespace.clone(),
+            annotations: Some(
+                BTreeMap::from([
+                    (String::from("beta.v1.sero/config"), sero_config_str.clone()),
+                ])
+            ),
+            labels: Some(
+                BTreeMap::from([
+                    (String::from("beta.v1.sero/deploy"), sero_config.deployment.clone()),
+                    (String::from("beta.v1.sero/service"), sero_config.service.clone()),
+                ])
+            ),
+            owner_references: owner.map(|o| vec![o.clone()]),
+            ..Default::default()
+        },
+        spec: Some(ServiceSpec {
+            selector: Some(
+                BTreeMap::from([
+                    (String::from("beta.v1.sero/deploy"), sero_config.deployment.clone()),
+                    (String::from("beta.v1.sero/service"), sero_config.service.clone()),
+                ])
+            ),
+            // same ports as the proxied service
+            ports: Some(ports.iter().map(|p| ServicePort{
+                name: Some(p.name.clone()),
+                port: p.port,
+                target_port: Some(IntOrString::Int(p.listen)),
+                protocol: Some(p.protocol.clone()),
+                ..Default::default()
+            }).collect()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let mut m = SeroManifests {
+        service_account: sa,
+        role,
+        role_binding: binding,
+        deployment,
+        config_map: configmap,
+        service: svc,
+    };
+    // rendered, written and applied objects carry the same hash
+    set_desired_hash(&mut m.service_account);
+    set_desired_hash(&mut m.role);
+    set_desired_hash(&mut m.role_binding);
+    set_desired_hash(&mut m.deployment);
+    set_desired_hash(&mut m.config_map);
+    set_desired_hash(&mut m.service);
+    m
+}
+
+pub async fn remove_sero_instance(client: &Client, sero_config: &SeroConfig, owner_uid: &str, apply: &ApplySettings, output: &OutputSettings) -> Result<()> {
+    let name = &sero_config.name_patern();
+    let namespace = &sero_config.namespace;
+    info!("removing Sero instance for {} in {}", name, namespace);
+    output::remove(client, sero_config, output, apply).await?;
+    if output.mode != OutputMode::Apply {
+        return Ok(());
+    }
+    let mut errors = vec![];
+    let deploy: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    match delete_owned(&deploy, name, owner_uid, apply).await {
+        Result::Ok(_) => {},
+        Err(e) => {warn!("error deleting {}", e); errors.push(format!("deploy {}", e))},
+    };
+    let cm: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+    match delete_owned(&cm, name, owner_uid, apply).await {
+        Result::Ok(_) => {},
+        Err(e) => {warn!("error deleting {}", e); errors.push(format!("cm {}", e))},
+    };
+    let svc: Api<Service> = Api::namespaced(client.clone(), namespace);
+    match delete_owned(&svc, name, owner_uid, apply).await {
+        Result::Ok(_) => {},
+        Err(e) => {warn!("error deleting {}", e); errors.push(format!("svc {}", e))},
+    };
+    let binding: Api<RoleBinding> = Api::namespaced(client.clone(), namespace);
+    match delete_owned(&binding, name, owner_uid, apply).await {
+        Result::Ok(_) => {},
+        Err(e) => {warn!("error deleting {}", e); errors.push(format!("rolebinding {}", e))},
+    };
+    let role: Api<Role> = Api::namespaced(client.clone(), namespace);
+    match delete_owned(&role, name, owner_uid, apply).await {
+        Result::Ok(_) => {},
+        Err(e) => {warn!("error deleting {}", e); errors.push(format!("role {}", e))},
+    };
+    let sa: Api<ServiceAccount> = Api::namespaced(client.clone(), namespace);
+    match delete_owned(&sa, name, owner_uid, apply).await {
+        Result::Ok(_) => {},
+        Err(e) => {warn!("error deleting {}", e); errors.push(format!("sa {}", e))},
+    };
+    if !errors.is_empty() {
+        bail!(errors.join(", "));
+    }
+    Ok(())
+}
+
+/// Hands the target back to the workload and removes the sero instance, returns `false` while the workload isn't available yet.
+///
+/// The workload is scaled up and the service pointed back at it before sero goes away, so the service is never left without pods.
+pub async fn teardown_sero_instance(client: &Client, sero_config: &SeroConfig, owner_uid: &str, scale: bool, apply: &ApplySettings, output: &OutputSettings) -> Result<bool> {
+    if !finalizer::restore_replicas(client, sero_config, scale, apply).await? {
+        return Ok(false);
+    }
+    finalizer::restore_selector(client, sero_config, apply).await?;
+    remove_sero_instance(client, sero_config, owner_uid, apply, output).await?;
+    Ok(true)
+}
+
+pub fn to_config(annotations: BTreeMap<String, String>, name: String, namespace: String, default: DefaultSeroConfig) -> Result<(SeroConfig, Vec<String>)> {
+    let mut invalid = vec![];
+    let mut builder = SeroConfigBuilder::new()
+        .deployment(name)
+        .namespace(namespace)
+        .image(default.image)
+        .inject(default.inject)
+        .inject_mode(default.inject_mode)
+        .protocol(default.protocol)
+        .port(default.port)
+        .drift(default.drift)
+        .timeout_forward(default.timeout.forward_ms)
+        .timeout_scale_up(default.timeout.scale_up_ms)
+        .timeout_scale_down(default.timeout.scale_down_ms);
+    let mut pod = PodSettings::default();
+    // with service ports an explicit port and protocol pick among them, unless `ports` does
+    let (mut port, mut protocol, mut ports) = (None, None, false);
+    for (k, v) in annotations.into_iter() {
+        builder = match k.to_lowercase().as_str() {
+            "beta.v1.sero/service" => {builder.service(v)}
+            "beta.v1.sero/inject" => {match v.parse::<bool>() {
+                Result::Ok(v) => {builder.inject(v)},
+                Err(_) => {warn!("can't parse {}={}. Using default.", k,v); invalid.push(format!("{}={}", k, v)); builder},
+            }},
+            "beta.v1.sero/inject-mode" => {match v.parse::<InjectMode>() {
+                Result::Ok(v) => {builder.inject_mode(v)},
+                Err(_) => {warn!("can't parse {}={}. Using default.", k,v); invalid.push(format!("{}={}", k, v)); builder},
+            }},
+            "beta.v1.sero/drift" => {match v.parse::<DriftPolicy>() {
+                Result::Ok(v) => {builder.drift(v)},
+                Err(_) => {warn!("can't parse {}={}. Using default.", k,v); invalid.push(format!("{}={}", k, v)); builder},
+            }},
+            "beta.v1.sero/protocol" => {match PROTOCOLS.contains(&v.to_uppercase().as_str()) {
+                true => {protocol = Some(v.to_uppercase()); builder.protocol(v.to_uppercase())},
+                false => {warn!("can't parse {}={}. Using default.", k,v); invalid.push(format!("{}={}", k, v)); builder},
+            }},
+            "beta.v1.sero/port" => {match v.parse::<u16>() {
+                Result::Ok(p) if p > 0 => {port = Some(p.into()); builder.port(p.into())},
+                _ => {warn!("can't parse {}={}. Using default.", k,v); invalid.push(format!("{}={}", k, v)); builder},
+            }},
+            "beta.v1.sero/ports" => {ports = true; builder.ports(v.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect())},
+            // json or yaml of the pod spec fields
+            "beta.v1.sero/resources" => {match serde_yaml::from_str(&v) {
+                Result::Ok(v) => {pod.resources = Some(v); builder},
+                Err(_) => {warn!("can't parse {}={}. Using default.", k,v); invalid.push(format!("{}={}", k, v)); builder},
+            }},
+            "beta.v1.sero/node-selector" => {match serde_yaml::from_str(&v) {
+                Result::Ok(v) => {pod.node_selector = Some(v); builder},
+                Err(_) => {warn!("can't parse {}={}. Using default.", k,v); invalid.push(format!("{}={}", k, v)); builder},
+            }},
+            "beta.v1.sero/tolerations" => {match serde_yaml::from_str(&v) {
+                Result::Ok(v) => {pod.tolerations = Some(v); builder},
+                Err(_) => {warn!("can't parse {}={}. Using default.", k,v); invalid.push(format!("{}={}", k, v)); builder},
+            }},
+            "beta.v1.sero/affinity" => {match serde_yaml::from_str(&v) {
+                Result::Ok(v) => {pod.affinity = Some(v); builder},
+                Err(_) => {warn!("can't parse {}={}. Using default.", k,v); invalid.push(format!("{}={}", k, v)); builder},
+            }},
+            "beta.v1.sero/priority-class-name" => {pod.priority_class_name = Some(v); builder},
+            "beta.v1.sero/topology-spread-constraints" => {match serde_yaml::from_str(&v) {
+                Result::Ok(v) => {pod.topology_spread_constraints = Some(v); builder},
+                Err(_) => {warn!("can't parse {}={}. Using default.", k,v); invalid.push(format!("{}={}", k, v)); builder},
+            }},
+            //"beta.v1.sero/deployment" => {builder.deployment(v)},
+            "beta.v1.sero/timeout-forward" => {match v.parse::<i64>() {
+                Result::Ok(v) => {builder.timeout_forward(v)},
+                Err(_) => {warn!("can't parse {}={}. Using default.", k,v); invalid.push(format!("{}={}", k, v)); builder},
+            }},
+            "beta.v1.sero/timeout-scaleup" => {match v.parse::<i64>() {
+                Result::Ok(v) => {builder.timeout_scale_up(v)},
+                Err(_) => {warn!("can't parse {}={}. Using default.", k,v); invalid.push(format!("{}={}", k, v)); builder},
+            }},
+            "beta.v1.sero/timeout-scale-down" => {match v.parse::<i64>() {
+                Result::Ok(v) => {builder.timeout_scale_down(v)},
+                Err(_) => {warn!("can't parse {}={}. Using default.", k,v); invalid.push(format!("{}={}", k, v)); builder},
+            }},
+            key if key.starts_with("beta.v1.sero/") && !annotation::OPERATOR_ANNOTATIONS.contains(&key) => {
+                warn!("unknown annotation {}. Ignoring.", k); invalid.push(format!("{}={}", k, v)); builder
+            },
+            _ => {builder},
+        };
+    }
+    if let (false, Some(selector)) = (ports, port_selector(port, protocol.as_deref())) {
+        builder = builder.ports(vec![selector]);
+    }
+    builder = builder.pod(default.pod.merge(pod));
+    Ok((builder.build()?, invalid))
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::ServiceSpec;
+
+    use super::*;
+
+    #[test]
+    fn shared_named_target_ports_get_one_container_port() {
+        let config = SeroConfigBuilder::new()
+            .deployment(String::from("app"))
+            .namespace(String::from("apps"))
+            .build()
+            .unwrap();
+        let port = |name: &str, port| ServicePort {
+            name: Some(name.to_string()),
+            port,
+            target_port: Some(IntOrString::String(String::from("web"))),
+            ..Default::default()
+        };
+        let svc = Service {
+            spec: Some(ServiceSpec { ports: Some(vec![port("http", 80), port("http-alt", 8000)]), ..Default::default() }),
+            ..Default::default()
+        };
+        let ports = config.proxied_ports(Some(&svc)).unwrap();
+        let m = sero_manifests(&config, None, &ports);
+        let container = &m.deployment.spec.unwrap().template.spec.unwrap().containers[0];
+        let container_ports: Vec<_> = container.ports.iter().flatten().map(|p| (p.container_port, p.name.clone())).collect();
+        assert_eq!(container_ports, vec![(8080, Some(String::from("web")))]);
+        assert_eq!(m.service.spec.unwrap().ports.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+    HistogramVec, IntCounterVec, IntGauge, IntGaugeVec,
+};
+
+pub static RECONCILE_TOTAL: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!(
+    "sero_operator_reconcile_total",
+    "reconciles by kind and outcome",
+    &["kind", "outcome"]
+).unwrap());
+
+pub static RECONCILE_DURATION: Lazy<HistogramVec> = Lazy::new(|| register_histogram_vec!(
+    "sero_operator_reconcile_duration_seconds",
+    "duration of reconciles by kind and outcome",
+    &["kind", "outcome"],
+    vec![0.01, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.]
+).unwrap());
+
+pub static MANAGED_INSTANCES: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!(
+    "sero_operator_managed_instances",
+    "sero instances managed by this replica per namespace",
+    &["namespace"]
+).unwrap());
+
+pub static WATCHER_RESTARTS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!(
+    "sero_operator_watcher_restarts_total",
+    "restarts of the watchers of a namespace",
+    &["namespace"]
+).unwrap());
+
+pub static EVENT_QUEUE_DEPTH: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!(
+    "sero_operator_event_queue_depth",
+    "namespace events waiting in the main event channel"
+).unwrap());
+
+pub static API_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!(
+    "sero_operator_api_errors_total",
+    "failed kubernetes api calls by resource kind and verb",
+    &["kind", "verb"]
+).unwrap());
+
+pub static DRY_RUN_WRITES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!(
+    "sero_operator_dry_run_writes_total",
+    "writes recorded instead of done in dry-run mode by resource kind and verb",
+    &["kind", "verb"]
+).unwrap());
+
+pub static DRIFT: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!(
+    "sero_operator_drift_total",
+    "generated objects found changed by someone else, by namespace, kind and whether they were repaired or reported",
+    &["namespace", "kind", "action"]
+).unwrap());
+
+// instance names per namespace, the gauge only holds the counts
+static INSTANCES: Lazy<Mutex<BTreeMap<String, BTreeSet<String>>>> = Lazy::new(Default::default);
+
+pub fn reconciled(kind: &str, ok: bool, duration: Duration) {
+    let outcome = if ok { "success" } else { "error" };
+    RECONCILE_TOTAL.with_label_values(&[kind, outcome]).inc();
+    RECONCILE_DURATION.with_label_values(&[kind, outcome]).observe(duration.as_secs_f64());
+}
+
+pub fn instance_managed(namespace: &str, name: &str, managed: bool) {
+    let mut instances = INSTANCES.lock().unwrap();
+    let names = instances.entry(namespace.to_string()).or_default();
+    if managed {
+        names.insert(name.to_string());
+    } else {
+        names.remove(name);
+    }
+    MANAGED_INSTANCES.with_label_values(&[namespace]).set(names.len() as i64);
+}
+
+/// Forgets all instances, a replica that lost the lease doesn't manage any.
+pub fn reset_instances() {
+    INSTANCES.lock().unwrap().clear();
+    MANAGED_INSTANCES.reset();
+}
+
+pub fn api_error(kind: &str, verb: &str) {
+    API_ERRORS.with_label_values(&[kind, verb]).inc();
+}
@@ -1,13 +1,30 @@
+use std::time::Duration;
+
 use config::{Config, ConfigError, File, FileFormat};
 use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::sero_config::{DriftPolicy, InjectMode, PodSettings};
 
 #[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 #[derive(Clone)]
 pub struct Settings {
+    #[serde(default)]
     pub namespaces: Vec<String>,
     #[serde(rename = "defaultConfig")]
     pub default_config: DefaultSeroConfig,
+    #[serde(default)]
+    pub apply: ApplySettings,
+    #[serde(default, rename = "leaderElection")]
+    pub leader_election: LeaderElectionSettings,
+    #[serde(default)]
+    pub http: HttpSettings,
+    #[serde(default)]
+    pub webhook: WebhookSettings,
+    #[serde(default)]
+    pub output: OutputSettings,
 }
 
 #[derive(Debug, PartialEq)]
@@ -16,9 +33,17 @@ pub struct Settings {
 pub struct DefaultSeroConfig {
     pub image: String,
     pub inject: bool,
+    #[serde(default, rename = "injectMode")]
+    pub inject_mode: InjectMode,
     pub protocol: String,
     pub port: i64,
     pub timeout: Timeout,
+    // resources and scheduling of the sero pods
+    #[serde(default)]
+    pub pod: PodSettings,
+    // repair or only report changes to generated objects
+    #[serde(default)]
+    pub drift: DriftPolicy,
 }
 
 #[derive(Debug, PartialEq)]
@@ -33,21 +58,237 @@ pub struct Timeout {
     pub scale_down_ms: i64,
 }
 
-const CONFIG_FILE_PREFIX: &str = "./config.yaml";
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+pub struct ApplySettings {
+    #[serde(rename = "fieldManager")]
+    pub field_manager: String,
+    // take over fields owned by other managers instead of failing with a conflict
+    pub force: bool,
+    #[serde(default, rename = "dryRun")]
+    pub dry_run: DryRun,
+}
+
+/// Whether the writes of the operator take effect.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DryRun {
+    /// writes are done
+    #[default]
+    None,
+    /// writes are sent with `dryRun=All`, the api server validates them without persisting
+    Server,
+    /// writes are only recorded
+    Skip,
+}
+
+impl Default for ApplySettings {
+    fn default() -> ApplySettings {
+        ApplySettings {
+            field_manager: String::from("sero-operator"),
+            force: true,
+            dry_run: DryRun::None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+pub struct LeaderElectionSettings {
+    pub enabled: bool,
+    #[serde(rename = "leaseName")]
+    pub lease_name: String,
+    // how long a lease is valid after its last renewal
+    #[serde(rename = "leaseDuration")]
+    pub lease_duration_s: u64,
+    // how long the leader keeps reconciling without a successful renewal
+    #[serde(rename = "renewDeadline")]
+    pub renew_deadline_s: u64,
+    #[serde(rename = "retryPeriod")]
+    pub retry_period_s: u64,
+}
+
+impl Default for LeaderElectionSettings {
+    fn default() -> LeaderElectionSettings {
+        LeaderElectionSettings {
+            enabled: true,
+            lease_name: String::from("sero-operator"),
+            lease_duration_s: 15,
+            renew_deadline_s: 10,
+            retry_period_s: 2,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+pub struct HttpSettings {
+    pub port: u16,
+}
+
+impl Default for HttpSettings {
+    fn default() -> HttpSettings {
+        HttpSettings {
+            port: 8080,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+#[serde(default)]
+pub struct WebhookSettings {
+    pub enabled: bool,
+    pub port: u16,
+    // reject deployments with invalid sero annotations, otherwise only warn
+    pub reject: bool,
+    // create and rotate the serving certificate instead of reading it from `certDir`
+    #[serde(rename = "selfSigned")]
+    pub self_signed: bool,
+    // directory holding tls.crt and tls.key of the serving certificate
+    #[serde(rename = "certDir")]
+    pub cert_dir: String,
+    // secret in the operator's namespace the self-signed certificate is stored in
+    #[serde(rename = "certSecret")]
+    pub cert_secret: String,
+    // service the webhook is reached by, the certificate is issued for it
+    pub service: String,
+    // name of the Validating/MutatingWebhookConfiguration whose caBundle is patched
+    pub configuration: String,
+    // days
+    #[serde(rename = "certValidity")]
+    pub cert_validity_days: i64,
+    #[serde(rename = "rotateBefore")]
+    pub rotate_before_days: i64,
+}
+
+impl Default for WebhookSettings {
+    fn default() -> WebhookSettings {
+        WebhookSettings {
+            enabled: false,
+            port: 8443,
+            reject: true,
+            self_signed: true,
+            cert_dir: String::from("/certs"),
+            cert_secret: String::from("sero-operator-webhook-cert"),
+            service: String::from("sero-operator-webhook"),
+            configuration: String::from("sero-operator"),
+            cert_validity_days: 365,
+            rotate_before_days: 30,
+        }
+    }
+}
+
+/// What happens with the generated objects of a sero instance.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputMode {
+    /// applied to the cluster
+    #[default]
+    Apply,
+    /// written as one file per instance into `directory`
+    Directory,
+    /// written as one key per instance into the ConfigMap `<configMap>-<namespace>` of the instance's namespace
+    ConfigMap,
+}
+
+#[derive(Debug, PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[derive(Clone)]
+#[serde(default)]
+pub struct OutputSettings {
+    pub mode: OutputMode,
+    pub directory: String,
+    // prefix of the ConfigMaps in the operator's namespace, one per namespace of the instances
+    #[serde(rename = "configMap")]
+    pub config_map: String,
+}
+
+impl Default for OutputSettings {
+    fn default() -> OutputSettings {
+        OutputSettings {
+            mode: OutputMode::Apply,
+            directory: String::from("/manifests"),
+            config_map: String::from("sero-manifests"),
+        }
+    }
+}
+
+pub const CONFIG_FILE_PREFIX: &str = "./config.yaml";
+// environment variables overriding single settings, e.g. SERO_OPERATOR_DEFAULT_CONFIG__TIMEOUT__SCALE_UP
+const ENV_PREFIX: &str = "SERO_OPERATOR_";
+// mounted ConfigMaps are updated by the kubelet without any event, so the file is polled
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where the settings are layered from: built-in defaults, the config file,
+/// `SERO_OPERATOR_*` environment variables and command-line overrides, each one over the previous.
+#[derive(Clone, Debug)]
+pub struct Sources {
+    // yaml, toml or json, picked by the extension
+    pub file: String,
+    // a missing file is fine unless it was given explicitly
+    pub file_required: bool,
+    // keys as in the config file, e.g. defaultConfig.timeout.forward
+    pub overrides: Vec<(String, String)>,
+}
+
+impl Default for Sources {
+    fn default() -> Sources {
+        Sources {
+            file: String::from(CONFIG_FILE_PREFIX),
+            file_required: false,
+            overrides: vec![],
+        }
+    }
+}
 
 impl Settings {
-    pub fn new() -> Result<Self, ConfigError> {
-        let s = Config::builder()
-        .add_source(File::new(CONFIG_FILE_PREFIX, FileFormat::Yaml))
-        // .add_source(File::new(CONFIG_FILE_PREFIX, FileFormat::Toml))
-        // .add_source(File::new(CONFIG_FILE_PREFIX, FileFormat::Json))
-        // todo: set default struct
-        .build();
-        
-        s.unwrap().try_deserialize::<Settings>()
-    }
-  }
-  
+    pub fn load(sources: &Sources) -> Result<Self, ConfigError> {
+        let format = match sources.file.rsplit('.').next() {
+            Some("toml") => FileFormat::Toml,
+            Some("json") => FileFormat::Json,
+            _ => FileFormat::Yaml,
+        };
+        let mut builder = Config::builder()
+            .add_source(Config::try_from(&Settings::default())?)
+            .add_source(File::new(&sources.file, format).required(sources.file_required));
+        for (k, v) in env_overrides(std::env::vars()).iter().chain(sources.overrides.iter()) {
+            builder = match k.as_str() {
+                "namespaces" => builder.set_override(k, v.split(',').filter(|n| !n.is_empty()).collect::<Vec<_>>())?,
+                _ => builder.set_override(k, v.as_str())?,
+            };
+        }
+        builder.build()?.try_deserialize::<Settings>()
+    }
+}
+
+/// Turns `SERO_OPERATOR_DEFAULT_CONFIG__TIMEOUT__SCALE_UP` into `defaultConfig.timeout.scaleUp`.
+fn env_overrides(vars: impl Iterator<Item = (String, String)>) -> Vec<(String, String)> {
+    let mut overrides: Vec<(String, String)> = vars
+        .filter_map(|(k, v)| Some((k.strip_prefix(ENV_PREFIX)?.to_string(), v)))
+        // the path of the config file itself
+        .filter(|(k, _)| k != "CONFIG")
+        .map(|(k, v)| {
+            let key = k.split("__")
+                .map(|segment| {
+                    let mut words = segment.split('_').filter(|w| !w.is_empty()).map(|w| w.to_lowercase());
+                    let first = words.next().unwrap_or_default();
+                    words.fold(first, |key, w| key + &w[..1].to_uppercase() + &w[1..])
+                })
+                .collect::<Vec<_>>()
+                .join(".");
+            (key, v)
+        })
+        .collect();
+    overrides.sort();
+    overrides
+}
 
 impl Default for Settings {
     fn default() -> Settings {
@@ -56,14 +297,137 @@ impl Default for Settings {
             default_config: DefaultSeroConfig {
                 image: String::from("ghcr.io/fluktuid/sero.rs:latest"),
                 inject: true,
+                inject_mode: InjectMode::Sero,
                 protocol: String::from("TCP"),
                 port: 80,
                 timeout: Timeout {
                     forward_ms: 2000,
                     scale_up_ms: 7000,
                     scale_down_ms: 7000,
-                }
-            }
+                },
+                pod: PodSettings::default(),
+                drift: DriftPolicy::Repair,
+            },
+            apply: ApplySettings::default(),
+            leader_election: LeaderElectionSettings::default(),
+            http: HttpSettings::default(),
+            webhook: WebhookSettings::default(),
+            output: OutputSettings::default(),
+        }
+    }
+}
+
+/// Reloads the settings whenever the config file changes.
+///
+/// Invalid files are ignored, the previous settings stay in place.
+pub async fn watch(sources: Sources, tx: watch::Sender<Settings>) {
+    let mut last = tokio::fs::read(&sources.file).await.ok();
+    loop {
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        let current = tokio::fs::read(&sources.file).await.ok();
+        // the kubelet swaps the file, it may be missing for a moment
+        if current.is_none() || current == last {
+            continue;
+        }
+        last = current;
+        let settings = match Settings::load(&sources) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("keeping previous settings: {}", e);
+                continue;
+            },
+        };
+        let previous = tx.borrow().clone();
+        if settings == previous {
+            continue;
         }
+        let webhook = WebhookSettings { reject: previous.webhook.reject, ..settings.webhook.clone() };
+        if settings.namespaces != previous.namespaces || settings.leader_election != previous.leader_election
+            || settings.http != previous.http || webhook != previous.webhook {
+            warn!("namespaces, leaderElection, http and webhook (except reject) are only applied on restart");
+        }
+        info!("reloaded settings from {}", sources.file);
+        tx.send_replace(settings);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Vec<_>>().into_iter()
+    }
+
+    // config file in the temp dir, unique per test as they run in parallel
+    fn file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("sero-operator-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn sources(file: String, overrides: &[(&str, &str)]) -> Sources {
+        Sources {
+            file,
+            file_required: true,
+            overrides: vars(overrides).collect(),
+        }
+    }
+
+    #[test]
+    fn env_vars_become_camel_case_keys() {
+        let overrides = env_overrides(vars(&[
+            ("SERO_OPERATOR_DEFAULT_CONFIG__TIMEOUT__SCALE_UP", "9000"),
+            ("SERO_OPERATOR_NAMESPACES", "a,b"),
+            ("SERO_OPERATOR_LEADER_ELECTION__ENABLED", "true"),
+        ]));
+        assert_eq!(overrides, vec![
+            (String::from("defaultConfig.timeout.scaleUp"), String::from("9000")),
+            (String::from("leaderElection.enabled"), String::from("true")),
+            (String::from("namespaces"), String::from("a,b")),
+        ]);
+    }
+
+    #[test]
+    fn other_env_vars_and_the_config_path_are_skipped() {
+        let overrides = env_overrides(vars(&[
+            ("SERO_OPERATOR_CONFIG", "/etc/sero/config.yaml"),
+            ("HOME", "/root"),
+            ("OTHER_DEFAULT_CONFIG__PORT", "81"),
+        ]));
+        assert!(overrides.is_empty());
+    }
+
+    #[test]
+    fn partial_files_keep_the_defaults() {
+        let settings = Settings::load(&sources(file("partial.yaml", "defaultConfig:\n  timeout:\n    forward: 3000\n"), &[])).unwrap();
+        let mut expected = Settings::default();
+        expected.default_config.timeout.forward_ms = 3000;
+        assert_eq!(settings, expected);
+    }
+
+    #[test]
+    fn the_format_is_picked_by_the_extension() {
+        let settings = Settings::load(&sources(file("config.toml", "[defaultConfig]\nport = 8080\n"), &[])).unwrap();
+        assert_eq!(settings.default_config.port, 8080);
+        let settings = Settings::load(&sources(file("config.json", r#"{"defaultConfig": {"port": 9090}}"#), &[])).unwrap();
+        assert_eq!(settings.default_config.port, 9090);
+    }
+
+    #[test]
+    fn overrides_win_over_the_file() {
+        let path = file("overridden.yaml", "namespaces: [a]\ndefaultConfig:\n  port: 8080\n");
+        let settings = Settings::load(&sources(path, &[("defaultConfig.port", "9090"), ("namespaces", "b,c")])).unwrap();
+        assert_eq!(settings.default_config.port, 9090);
+        assert_eq!(settings.namespaces, vec!["b", "c"]);
+    }
+
+    #[test]
+    fn invalid_or_missing_required_files_fail() {
+        assert!(Settings::load(&sources(file("invalid.yaml", "defaultConfig:\n  port: eighty\n"), &[])).is_err());
+        assert!(Settings::load(&sources(file("empty.yaml", ""), &[])).is_ok());
+        let missing = std::env::temp_dir().join("sero-operator-does-not-exist.yaml").to_string_lossy().to_string();
+        assert!(Settings::load(&sources(missing.clone(), &[])).is_err());
+        assert!(Settings::load(&Sources { file_required: false, ..sources(missing, &[]) }).is_ok());
     }
 }
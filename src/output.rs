@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::api::Patch;
+use kube::core::ObjectMeta;
+use kube::{Api, Client};
+use serde_json::json;
+use tracing::{debug, info};
+
+use crate::apply::{dry_run, patch_params, post_params};
+use crate::metrics;
+use crate::operator_config::{ApplySettings, OutputMode, OutputSettings};
+use crate::sero_config::SeroConfig;
+
+/// File of an instance in the output directory or key in the output ConfigMap.
+///
+/// Only depends on the namespace and deployment, so every reconcile overwrites the same file.
+pub fn file_name(sero_config: &SeroConfig) -> String {
+    format!("{}_{}.yaml", sero_config.namespace, sero_config.name_patern())
+}
+
+/// Output ConfigMap of the instances of a namespace.
+///
+/// A ConfigMap holds at most 1 MiB, so the instances are split by namespace.
+pub fn config_map_name(sero_config: &SeroConfig, settings: &OutputSettings) -> String {
+    format!("{}-{}", settings.config_map, sero_config.namespace)
+}
+
+// data of a ConfigMap, leaving room for its metadata below the 1 MiB limit
+const MAX_CONFIG_MAP_DATA: usize = 1000 * 1000;
+
+/// Whether `data` stays below the ConfigMap limit with `yaml` as `file`.
+fn fits(data: Option<&BTreeMap<String, String>>, file: &str, yaml: &str) -> bool {
+    let others: usize = data.iter()
+        .flat_map(|d| d.iter())
+        .filter(|(k, _)| k.as_str() != file)
+        .map(|(k, v)| k.len() + v.len())
+        .sum();
+    others + file.len() + yaml.len() <= MAX_CONFIG_MAP_DATA
+}
+
+/// Writes the rendered manifests of an instance, skipping the write if they didn't change.
+pub async fn write(client: &Client, sero_config: &SeroConfig, yaml: &str, settings: &OutputSettings, apply: &ApplySettings) -> Result<()> {
+    let file = file_name(sero_config);
+    match settings.mode {
+        OutputMode::Apply => Ok(()),
+        OutputMode::Directory => {
+            let path = Path::new(&settings.directory).join(&file);
+            if tokio::fs::read_to_string(&path).await.ok().as_deref() == Some(yaml) {
+                debug!("{} is up to date", path.display());
+                return Ok(());
+            }
+            // files have no server-side dry run
+            if dry_run(apply, "write", "File", &settings.directory, &file) != Some(false) {
+                return Ok(());
+            }
+            info!("writing {}", path.display());
+            tokio::fs::create_dir_all(&settings.directory).await?;
+            // a pipeline picking up the directory never sees half a file
+            let tmp = Path::new(&settings.directory).join(format!(".{}.tmp", file));
+            tokio::fs::write(&tmp, yaml).await?;
+            tokio::fs::rename(&tmp, &path).await?;
+            Ok(())
+        },
+        OutputMode::ConfigMap => {
+            let namespace = client.default_namespace().to_string();
+            let name = config_map_name(sero_config, settings);
+            let api: Api<ConfigMap> = Api::default_namespaced(client.clone());
+            let current = api.get_opt(&name).await.inspect_err(|_| metrics::api_error("ConfigMap", "get"))?;
+            if !fits(current.as_ref().and_then(|c| c.data.as_ref()), &file, yaml) {
+                bail!("{} doesn't fit into configmap {}, ConfigMaps hold at most 1 MiB, use the directory output instead", file, name)
+            }
+            let current = match current {
+                Some(v) => v,
+                None => {
+                    let cm = ConfigMap {
+                        metadata: ObjectMeta {
+                            name: Some(name.clone()),
+                            ..Default::default()
+                        },
+                        data: Some(BTreeMap::from([(file, yaml.to_string())])),
+                        ..Default::default()
+                    };
+                    if let Some(params) = post_params(apply, "create", "ConfigMap", &namespace, &name) {
+                        info!("creating output configmap {}", name);
+                        api.create(&params, &cm).await.inspect_err(|_| metrics::api_error("ConfigMap", "create"))?;
+                    }
+                    return Ok(());
+                },
+            };
+            if current.data.as_ref().and_then(|d| d.get(&file)).map(String::as_str) == Some(yaml) {
+                debug!("{} in {} is up to date", file, name);
+                return Ok(());
+            }
+            // a merge patch only touches the key of this instance
+            let patch = json!({ "data": { &file: yaml } });
+            if let Some(params) = patch_params(apply, "patch", "ConfigMap", &namespace, &name) {
+                info!("writing {} to configmap {}", file, name);
+                api.patch(&name, &params, &Patch::Merge(&patch)).await
+                    .inspect_err(|_| metrics::api_error("ConfigMap", "patch"))?;
+            }
+            Ok(())
+        },
+    }
+}
+
+/// Removes the manifests of an instance, so the pipeline deletes its objects.
+pub async fn remove(client: &Client, sero_config: &SeroConfig, settings: &OutputSettings, apply: &ApplySettings) -> Result<()> {
+    let file = file_name(sero_config);
+    match settings.mode {
+        OutputMode::Apply => Ok(()),
+        OutputMode::Directory => {
+            let path = Path::new(&settings.directory).join(&file);
+            if !path.exists() || dry_run(apply, "remove", "File", &settings.directory, &file) != Some(false) {
+                return Ok(());
+            }
+            match tokio::fs::remove_file(&path).await {
+                Ok(_) => {
+                    info!("removed {}", path.display());
+                    Ok(())
+                },
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e.into()),
+            }
+        },
+        OutputMode::ConfigMap => {
+            let namespace = client.default_namespace().to_string();
+            let name = config_map_name(sero_config, settings);
+            let api: Api<ConfigMap> = Api::default_namespaced(client.clone());
+            let current = api.get_opt(&name).await.inspect_err(|_| metrics::api_error("ConfigMap", "get"))?;
+            let present = current.and_then(|c| c.data).map(|d| d.contains_key(&file)).unwrap_or(false);
+            if !present {
+                return Ok(());
+            }
+            let patch = json!({ "data": { &file: null } });
+            if let Some(params) = patch_params(apply, "patch", "ConfigMap", &namespace, &name) {
+                info!("removing {} from configmap {}", file, name);
+                api.patch(&name, &params, &Patch::Merge(&patch)).await
+                    .inspect_err(|_| metrics::api_error("ConfigMap", "patch"))?;
+            }
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configmap_data_is_limited() {
+        let data = BTreeMap::from([(String::from("a.yaml"), "x".repeat(MAX_CONFIG_MAP_DATA / 2))]);
+        assert!(fits(None, "b.yaml", "y"));
+        assert!(fits(Some(&data), "b.yaml", &"y".repeat(MAX_CONFIG_MAP_DATA / 4)));
+        assert!(!fits(Some(&data), "b.yaml", &"y".repeat(MAX_CONFIG_MAP_DATA / 2)));
+        // the current version of a file doesn't count
+        assert!(fits(Some(&data), "a.yaml", &"y".repeat(MAX_CONFIG_MAP_DATA - 10)));
+    }
+}
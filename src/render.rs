@@ -0,0 +1,203 @@
+use anyhow::{bail, Result};
+use k8s_openapi::api::apps::v1::Deployment;
+use k8s_openapi::api::core::v1::Service;
+use kube::ResourceExt;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::api::annotation::{get_type, AppType};
+use crate::crd::SeroProxy;
+use crate::operator_config::DefaultSeroConfig;
+use crate::sero_config::{ProxiedPort, SeroConfig};
+use crate::{po_to_cfg, sero_manifests};
+
+/// Deployments, SeroProxies and Services read from manifest files.
+#[derive(Default)]
+pub struct Manifests {
+    pub deployments: Vec<Deployment>,
+    pub proxies: Vec<SeroProxy>,
+    pub services: Vec<Service>,
+}
+
+impl Manifests {
+    /// Adds the objects of a multi-document yaml, `List`s are unpacked and other kinds skipped.
+    pub fn read(&mut self, yaml: &str) -> Result<()> {
+        for doc in serde_yaml::Deserializer::from_str(yaml) {
+            self.add(serde_yaml::Value::deserialize(doc)?)?;
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, value: serde_yaml::Value) -> Result<()> {
+        match value.get("kind").and_then(|k| k.as_str()) {
+            Some("Deployment") => self.deployments.push(serde_yaml::from_value(value)?),
+            Some("SeroProxy") => self.proxies.push(serde_yaml::from_value(value)?),
+            Some("Service") => self.services.push(serde_yaml::from_value(value)?),
+            Some("List") | Some("DeploymentList") | Some("ServiceList") | Some("SeroProxyList") => {
+                for item in value.get("items").and_then(|i| i.as_sequence()).cloned().unwrap_or_default() {
+                    self.add(item)?;
+                }
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Effective configs of the sero instances, objects without a namespace are placed in `namespace`.
+    pub fn configs(&self, namespace: &str, default: &DefaultSeroConfig) -> Result<Vec<SeroConfig>> {
+        let mut configs = vec![];
+        let mut errors = vec![];
+        for d in &self.deployments {
+            if !matches!(get_type(d.annotations()), AppType::Managed) {
+                continue;
+            }
+            let ns = d.namespace().unwrap_or_else(|| namespace.to_string());
+            match po_to_cfg(d.clone(), ns.clone(), default.clone()) {
+                Ok((config, invalid)) => {
+                    if !invalid.is_empty() {
+                        warn!("deployment {}/{} uses defaults for {}", ns, d.name_any(), invalid.join(", "));
+                    }
+                    configs.push(config);
+                },
+                Err(e) => errors.push(format!("deployment {}/{}: {}", ns, d.name_any(), e)),
+            }
+        }
+        for p in &self.proxies {
+            let ns = p.namespace().unwrap_or_else(|| namespace.to_string());
+            match p.spec.to_config(ns.clone(), default.clone()) {
+                Ok(config) => configs.push(config),
+                Err(e) => errors.push(format!("seroproxy {}/{}: {}", ns, p.name_any(), e)),
+            }
+        }
+        if !errors.is_empty() {
+            bail!(errors.join(", "))
+        }
+        Ok(configs)
+    }
+
+    /// The target service of an instance, if it is part of the manifests.
+    pub fn service(&self, config: &SeroConfig) -> Option<&Service> {
+        self.services.iter().find(|s| {
+            s.name_any() == config.service && s.namespace().as_ref().unwrap_or(&config.namespace) == &config.namespace
+        })
+    }
+}
+
+/// Renders the objects the operator creates for a sero instance, without owner references.
+///
+/// Without the target service in `manifests` the ports fall back to `port` and `protocol` of the config.
+pub fn render(config: &SeroConfig, manifests: &Manifests) -> Result<String> {
+    let target = manifests.service(config);
+    if target.is_none() {
+        warn!("service {}/{} not in the manifests, proxying port {}", config.namespace, config.service, config.port);
+    }
+    document(config, &config.proxied_ports(target)?)
+}
+
+/// The objects of a sero instance as one yaml document stream, without owner references.
+pub fn document(config: &SeroConfig, ports: &[ProxiedPort]) -> Result<String> {
+    let header = format!("# sero instance of deployment {}/{}\n", config.namespace, config.deployment);
+    Ok(header + &sero_manifests(config, None, ports).to_yaml()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::operator_config::Settings;
+
+    const MANIFESTS: &str = r#"
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  name: web
+  namespace: shop
+  annotations:
+    beta.v1.sero/service: web-svc
+---
+apiVersion: v1
+kind: List
+items:
+- apiVersion: v1
+  kind: Service
+  metadata:
+    name: web-svc
+    namespace: shop
+  spec:
+    ports:
+    - name: http
+      port: 80
+      targetPort: 3000
+- apiVersion: sero.rs/v1beta1
+  kind: SeroProxy
+  metadata:
+    name: api
+  spec:
+    deployment: api
+    port: 9000
+- apiVersion: v1
+  kind: ConfigMap
+  metadata:
+    name: ignored
+"#;
+
+    fn manifests(yaml: &str) -> Manifests {
+        let mut manifests = Manifests::default();
+        manifests.read(yaml).unwrap();
+        manifests
+    }
+
+    fn default() -> DefaultSeroConfig {
+        Settings::default().default_config
+    }
+
+    #[test]
+    fn documents_and_lists_are_read() {
+        let m = manifests(MANIFESTS);
+        assert_eq!(m.deployments.len(), 1);
+        assert_eq!(m.services.len(), 1);
+        assert_eq!(m.proxies.len(), 1);
+    }
+
+    #[test]
+    fn objects_without_namespace_are_placed_in_the_default_one() {
+        let configs = manifests(MANIFESTS).configs("apps", &default()).unwrap();
+        let namespaces: Vec<_> = configs.iter().map(|c| (c.deployment.as_str(), c.namespace.as_str())).collect();
+        assert_eq!(namespaces, vec![("web", "shop"), ("api", "apps")]);
+    }
+
+    #[test]
+    fn ports_come_from_the_target_service_or_the_config() {
+        let m = manifests(MANIFESTS);
+        let configs = m.configs("apps", &default()).unwrap();
+        // <listen port>:<service port>/<protocol>
+        assert!(render(&configs[0], &m).unwrap().contains("PORTS: 3000:80/TCP"));
+        assert!(render(&configs[1], &m).unwrap().contains("PORTS: 8080:9000/TCP"));
+    }
+
+    #[test]
+    fn invalid_configs_fail() {
+        let m = manifests(r#"
+apiVersion: sero.rs/v1beta1
+kind: SeroProxy
+metadata:
+  name: api
+spec:
+  deployment: api
+  protocol: HTTP
+"#);
+        assert!(m.configs("apps", &default()).is_err());
+    }
+
+    #[test]
+    fn deployments_without_name_fail() {
+        let m = manifests(r#"
+apiVersion: apps/v1
+kind: Deployment
+metadata:
+  generateName: web-
+  annotations:
+    beta.v1.sero/service: web-svc
+"#);
+        assert!(m.configs("apps", &default()).is_err());
+    }
+}
@@ -1,7 +1,61 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+
+use k8s_openapi::api::core::v1::{Affinity, ResourceRequirements, Service, ServicePort, Toleration, TopologySpreadConstraint};
+use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use anyhow::bail;
 use tracing::warn;
 
+/// Who routes the target service to sero while the workload is scaled to zero.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum InjectMode {
+    /// sero patches the service itself
+    #[default]
+    Sero,
+    /// the operator rewrites the service selector
+    Operator,
+}
+
+impl FromStr for InjectMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<InjectMode, anyhow::Error> {
+        match s {
+            "sero" => Ok(InjectMode::Sero),
+            "operator" => Ok(InjectMode::Operator),
+            _ => bail!("unknown inject mode {}", s),
+        }
+    }
+}
+
+/// What happens when generated objects were changed by someone else.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DriftPolicy {
+    /// the desired state is applied again
+    #[default]
+    Repair,
+    /// the change is kept and reported in the status
+    Report,
+}
+
+impl FromStr for DriftPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<DriftPolicy, anyhow::Error> {
+        match s {
+            "repair" => Ok(DriftPolicy::Repair),
+            "report" => Ok(DriftPolicy::Report),
+            _ => bail!("unknown drift policy {}", s),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct SeroConfig {
@@ -9,16 +63,157 @@ pub struct SeroConfig {
     pub image: String,
     pub service: String,
     pub service_inject: bool,
+    #[serde(default)]
+    pub inject_mode: InjectMode,
     pub deployment: String,
+    pub namespace: String,
+    pub protocol: String,
+    pub port: i64,
+    // names or numbers of the service ports proxied, all if empty
+    #[serde(default)]
+    pub ports: Vec<String>,
+    #[serde(default)]
+    pub pod: PodSettings,
+    #[serde(default)]
+    pub drift: DriftPolicy,
     pub timeout_forward_ms: i64,
     pub timeout_scale_up_ms: i64,
     pub timeout_scale_down_ms: i64,
 }
 
+// protocols a ServicePort accepts
+pub const PROTOCOLS: [&str; 3] = ["TCP", "UDP", "SCTP"];
+
+// sero listens from here on for ports without a numeric target port
+const FIRST_LISTEN_PORT: i32 = 8080;
+
+/// Resources and scheduling of the sero pods.
+#[derive(Debug, PartialEq, Clone, Default)]
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PodSettings {
+    pub resources: Option<ResourceRequirements>,
+    pub node_selector: Option<BTreeMap<String, String>>,
+    pub tolerations: Option<Vec<Toleration>>,
+    pub affinity: Option<Affinity>,
+    pub priority_class_name: Option<String>,
+    pub topology_spread_constraints: Option<Vec<TopologySpreadConstraint>>,
+}
+
+impl PodSettings {
+    /// Every field set in `over` replaces the one of `self`.
+    pub fn merge(self, over: PodSettings) -> PodSettings {
+        PodSettings {
+            resources: over.resources.or(self.resources),
+            node_selector: over.node_selector.or(self.node_selector),
+            tolerations: over.tolerations.or(self.tolerations),
+            affinity: over.affinity.or(self.affinity),
+            priority_class_name: over.priority_class_name.or(self.priority_class_name),
+            topology_spread_constraints: over.topology_spread_constraints.or(self.topology_spread_constraints),
+        }
+    }
+}
+
+/// A port of the target service proxied by sero.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProxiedPort {
+    pub name: String,
+    pub port: i32,
+    pub protocol: String,
+    // the target port of the service, so the service can be routed to sero unchanged
+    pub listen: i32,
+    // name of a named target port
+    pub listen_name: Option<String>,
+}
+
+/// Selects the service ports an explicit `port` and `protocol` pick, either may be unset.
+pub fn port_selector(port: Option<i64>, protocol: Option<&str>) -> Option<String> {
+    match (port, protocol) {
+        (None, None) => None,
+        (port, protocol) => Some(format!(
+            "{}{}",
+            port.map(|p| p.to_string()).unwrap_or_default(),
+            protocol.map(|p| format!("/{}", p.to_uppercase())).unwrap_or_default(),
+        )),
+    }
+}
+
+/// Whether `<name or number>/<protocol>` selects the service port, an empty part matches any.
+fn selects(selector: &str, p: &ServicePort) -> bool {
+    let (port, protocol) = selector.split_once('/').unwrap_or((selector, ""));
+    (port.is_empty() || Some(port) == p.name.as_deref() || port == p.port.to_string())
+        && (protocol.is_empty() || protocol.eq_ignore_ascii_case(p.protocol.as_deref().unwrap_or("TCP")))
+}
+
 impl SeroConfig {
     pub fn name_patern(&self) -> String {
         format!("sero-{}", self.deployment)
     }
+
+    /// Ports of the target service sero proxies, `port` and `protocol` if the service has none.
+    pub fn proxied_ports(&self, target: Option<&Service>) -> Result<Vec<ProxiedPort>, anyhow::Error> {
+        let service_ports = target
+            .and_then(|s| s.spec.as_ref())
+            .and_then(|s| s.ports.clone())
+            .unwrap_or_default();
+        if service_ports.is_empty() {
+            return Ok(vec![ProxiedPort {
+                name: format!("{}-{}", self.protocol.to_lowercase(), self.port),
+                port: self.port as i32,
+                protocol: self.protocol.clone(),
+                listen: FIRST_LISTEN_PORT,
+                listen_name: None,
+            }]);
+        }
+        let selected: Vec<_> = service_ports.into_iter()
+            .filter(|p| self.ports.is_empty() || self.ports.iter().any(|r| selects(r, p)))
+            .collect();
+        if selected.is_empty() {
+            bail!("none of the ports {} found in service {}", self.ports.join(","), self.service)
+        }
+        // numeric target ports first, so the ones picked for named ports don't collide with them
+        let mut used = BTreeSet::new();
+        let wanted: Vec<Option<i32>> = selected.iter()
+            .map(|p| match &p.target_port {
+                Some(IntOrString::Int(n)) => Some(*n),
+                Some(IntOrString::String(_)) => None,
+                None => Some(p.port),
+            })
+            .map(|n| n.filter(|n| used.insert(*n)))
+            .collect();
+        let mut next = FIRST_LISTEN_PORT;
+        // service ports sharing a named target port share its listen port
+        let mut named = BTreeMap::new();
+        Ok(selected.into_iter().zip(wanted).map(|(p, wanted)| {
+            let listen = wanted.unwrap_or_else(|| {
+                let name = match &p.target_port {
+                    Some(IntOrString::String(n)) => n.clone(),
+                    _ => String::new(),
+                };
+                *named.entry(name).or_insert_with(|| {
+                    while used.contains(&next) { next += 1; }
+                    used.insert(next);
+                    next
+                })
+            });
+            let protocol = p.protocol.unwrap_or_else(|| String::from("TCP"));
+            ProxiedPort {
+                name: p.name.unwrap_or_else(|| format!("{}-{}", protocol.to_lowercase(), p.port)),
+                port: p.port,
+                protocol,
+                listen,
+                listen_name: match p.target_port {
+                    Some(IntOrString::String(n)) => Some(n),
+                    _ => None,
+                },
+            }
+        }).collect())
+    }
+
+    /// Whether the operator routes the target service instead of sero.
+    pub fn operator_inject(&self) -> bool {
+        self.service_inject && self.inject_mode == InjectMode::Operator
+    }
 }
 
 impl Default for SeroConfig {
@@ -26,11 +221,18 @@ impl Default for SeroConfig {
         SeroConfig {
             image: String::from("ghcr.io/fluktuid/sero.rs"),
             service_inject: true,
+            inject_mode: InjectMode::Sero,
             timeout_forward_ms: 2000,
             timeout_scale_up_ms: 5000,
             timeout_scale_down_ms: 15000,
             service: String::new(),
             deployment: String::new(),
+            namespace: String::new(),
+            protocol: String::from("TCP"),
+            port: 80,
+            ports: vec![],
+            pod: PodSettings::default(),
+            drift: DriftPolicy::Repair,
         }
     }
 }
@@ -40,7 +242,14 @@ pub struct SeroConfigBuilder {
     image: Option<String>,
     service: Option<String>,
     service_inject: bool,
+    inject_mode: InjectMode,
     deployment: Option<String>,
+    namespace: Option<String>,
+    protocol: Option<String>,
+    port: Option<i64>,
+    ports: Vec<String>,
+    pod: PodSettings,
+    drift: DriftPolicy,
     timeout_forward_ms: i64,
     timeout_scale_up_ms: i64,
     timeout_scale_down_ms: i64,
@@ -51,6 +260,7 @@ impl SeroConfigBuilder {
         SeroConfigBuilder {
             service: None,
             deployment: None,
+            namespace: None,
             ..Default::default()
         }
     }
@@ -63,6 +273,10 @@ impl SeroConfigBuilder {
         self.service_inject = inject; self
     }
 
+    pub fn inject_mode(mut self, inject_mode: InjectMode) -> SeroConfigBuilder {
+        self.inject_mode = inject_mode; self
+    }
+
     pub fn image(mut self, image: String) -> SeroConfigBuilder {
         self.image = Some(image); self
     }
@@ -71,6 +285,30 @@ impl SeroConfigBuilder {
         self.deployment = Some(deployment); self
     }
 
+    pub fn namespace(mut self, namespace: String) -> SeroConfigBuilder {
+        self.namespace = Some(namespace); self
+    }
+
+    pub fn protocol(mut self, protocol: String) -> SeroConfigBuilder {
+        self.protocol = Some(protocol); self
+    }
+
+    pub fn port(mut self, port: i64) -> SeroConfigBuilder {
+        self.port = Some(port); self
+    }
+
+    pub fn ports(mut self, ports: Vec<String>) -> SeroConfigBuilder {
+        self.ports = ports; self
+    }
+
+    pub fn pod(mut self, pod: PodSettings) -> SeroConfigBuilder {
+        self.pod = pod; self
+    }
+
+    pub fn drift(mut self, drift: DriftPolicy) -> SeroConfigBuilder {
+        self.drift = drift; self
+    }
+
     pub fn timeout_forward(mut self, millis: i64) -> SeroConfigBuilder {
         self.timeout_forward_ms = millis; self
     }
@@ -87,18 +325,154 @@ impl SeroConfigBuilder {
         if self.deployment.is_none() {
             bail!("Missing attribute: deployment")
         }
+        if self.namespace.is_none() {
+            bail!("Missing attribute: namespace")
+        }
         if self.service.is_none() {
             warn!("Missing attribute: service. Using deployment name.")
         }
+        let protocol = self.protocol.unwrap_or(SeroConfig::default().protocol).to_uppercase();
+        if !PROTOCOLS.contains(&protocol.as_str()) {
+            bail!("Invalid attribute: protocol {} is none of {}", protocol, PROTOCOLS.join(", "))
+        }
+        let port = self.port.unwrap_or(SeroConfig::default().port);
+        if !(1..=65535).contains(&port) {
+            bail!("Invalid attribute: port {} is out of range", port)
+        }
         let deploy = self.deployment.unwrap();
         Ok(SeroConfig {
+            image: self.image.unwrap_or(SeroConfig::default().image),
             service: self.service.unwrap_or(deploy.clone()),
             service_inject: self.service_inject,
+            inject_mode: self.inject_mode,
             deployment: deploy,
+            namespace: self.namespace.unwrap(),
+            protocol,
+            port,
+            ports: self.ports,
+            pod: self.pod,
+            drift: self.drift,
             timeout_forward_ms: self.timeout_forward_ms,
             timeout_scale_up_ms: self.timeout_scale_up_ms,
             timeout_scale_down_ms: self.timeout_scale_down_ms,
-            ..Default::default()
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::ServiceSpec;
+
+    use super::*;
+
+    fn config(ports: &[&str]) -> SeroConfig {
+        SeroConfigBuilder::new()
+            .deployment(String::from("app"))
+            .namespace(String::from("apps"))
+            .ports(ports.iter().map(|p| p.to_string()).collect())
+            .build()
+            .unwrap()
+    }
+
+    fn port(name: &str, port: i32, target: Option<IntOrString>) -> ServicePort {
+        ServicePort {
+            name: Some(name.to_string()),
+            port,
+            target_port: target,
+            ..Default::default()
+        }
+    }
+
+    fn service(ports: Vec<ServicePort>) -> Service {
+        Service {
+            spec: Some(ServiceSpec {
+                ports: Some(ports),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn listen(ports: &[ProxiedPort]) -> Vec<(i32, Option<&str>)> {
+        ports.iter().map(|p| (p.listen, p.listen_name.as_deref())).collect()
+    }
+
+    #[test]
+    fn without_service_ports_port_and_protocol_are_used() {
+        let ports = config(&[]).proxied_ports(None).unwrap();
+        assert_eq!(ports, vec![ProxiedPort {
+            name: String::from("tcp-80"),
+            port: 80,
+            protocol: String::from("TCP"),
+            listen: FIRST_LISTEN_PORT,
+            listen_name: None,
+        }]);
+    }
+
+    #[test]
+    fn numeric_target_ports_are_kept() {
+        let svc = service(vec![
+            port("http", 80, Some(IntOrString::Int(3000))),
+            port("metrics", 9090, None),
+        ]);
+        let ports = config(&[]).proxied_ports(Some(&svc)).unwrap();
+        assert_eq!(listen(&ports), vec![(3000, None), (9090, None)]);
+        assert_eq!(ports[0].protocol, "TCP");
+    }
+
+    #[test]
+    fn named_target_ports_get_a_free_port_and_keep_their_name() {
+        let svc = service(vec![
+            port("http", 80, Some(IntOrString::String(String::from("web")))),
+            port("admin", 8081, Some(IntOrString::Int(8080))),
+        ]);
+        let ports = config(&[]).proxied_ports(Some(&svc)).unwrap();
+        assert_eq!(listen(&ports), vec![(8081, Some("web")), (8080, None)]);
+    }
+
+    #[test]
+    fn shared_named_target_ports_share_their_listen_port() {
+        let svc = service(vec![
+            port("http", 80, Some(IntOrString::String(String::from("web")))),
+            port("http-alt", 8000, Some(IntOrString::String(String::from("web")))),
+            port("admin", 8081, Some(IntOrString::String(String::from("admin")))),
+        ]);
+        let ports = config(&[]).proxied_ports(Some(&svc)).unwrap();
+        assert_eq!(listen(&ports), vec![(8080, Some("web")), (8080, Some("web")), (8081, Some("admin"))]);
+    }
+
+    #[test]
+    fn duplicate_target_ports_are_moved() {
+        let svc = service(vec![
+            port("http", 80, Some(IntOrString::Int(8080))),
+            port("alt", 8000, Some(IntOrString::Int(8080))),
+        ]);
+        let ports = config(&[]).proxied_ports(Some(&svc)).unwrap();
+        assert_eq!(listen(&ports), vec![(8080, None), (8081, None)]);
+    }
+
+    #[test]
+    fn ports_are_filtered_by_name_or_number() {
+        let svc = service(vec![
+            port("http", 80, None),
+            port("https", 443, None),
+            port("metrics", 9090, None),
+        ]);
+        let ports = config(&["http", "9090"]).proxied_ports(Some(&svc)).unwrap();
+        assert_eq!(ports.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(), vec!["http", "metrics"]);
+        assert!(config(&["grpc"]).proxied_ports(Some(&svc)).is_err());
+    }
+
+    #[test]
+    fn ports_are_filtered_by_protocol() {
+        let udp = |name: &str, number: i32| ServicePort { protocol: Some(String::from("UDP")), ..port(name, number, None) };
+        let svc = service(vec![port("dns-tcp", 53, None), udp("dns", 53), udp("metrics", 9090)]);
+        let names = |ports: &[&str]| config(ports).proxied_ports(Some(&svc)).unwrap().into_iter().map(|p| p.name).collect::<Vec<_>>();
+        assert_eq!(names(&["53/udp"]), vec!["dns"]);
+        assert_eq!(names(&["/UDP"]), vec!["dns", "metrics"]);
+        assert_eq!(names(&["53"]), vec!["dns-tcp", "dns"]);
+        assert_eq!(port_selector(Some(53), Some("udp")).as_deref(), Some("53/UDP"));
+        assert_eq!(port_selector(None, Some("udp")).as_deref(), Some("/UDP"));
+        assert_eq!(port_selector(None, None), None);
+    }
+}
@@ -0,0 +1,65 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, routing::get, Router};
+use prometheus::{Encoder, TextEncoder};
+use tokio::{sync::RwLock, task::JoinHandle};
+use tracing::info;
+
+use crate::api::annotation::AnnotationWatcher;
+
+/// What `/healthz` and `/readyz` report on.
+#[derive(Clone)]
+pub struct Health {
+    pub watcher: Arc<RwLock<AnnotationWatcher>>,
+    // whether the watched namespaces are known, set once a static list was added
+    pub namespaces_listed: Arc<AtomicBool>,
+    pub namespace_watcher: Option<Arc<JoinHandle<()>>>,
+}
+
+pub async fn serve(port: u16, health: Health) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(health);
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    info!("serving http on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+async fn metrics() -> Result<String, StatusCode> {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    String::from_utf8(buffer).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn healthz(State(health): State<Health>) -> (StatusCode, String) {
+    if let Some(h) = &health.namespace_watcher {
+        if h.is_finished() {
+            return (StatusCode::SERVICE_UNAVAILABLE, "namespace watcher stopped".to_string());
+        }
+    }
+    let dead = health.watcher.read().await.dead().await;
+    if !dead.is_empty() {
+        return (StatusCode::SERVICE_UNAVAILABLE, format!("controllers stopped for ns {}", dead.join(", ")));
+    }
+    (StatusCode::OK, "ok".to_string())
+}
+
+async fn readyz(State(health): State<Health>) -> (StatusCode, String) {
+    if !health.namespaces_listed.load(Ordering::Relaxed) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "namespaces not listed yet".to_string());
+    }
+    let pending = health.watcher.read().await.pending().await;
+    if !pending.is_empty() {
+        return (StatusCode::SERVICE_UNAVAILABLE, format!("waiting for initial list of ns {}", pending.join(", ")));
+    }
+    (StatusCode::OK, "ok".to_string())
+}
@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use k8s_openapi::api::apps::v1::Deployment;
+use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{Condition, Time};
+use k8s_openapi::chrono::Utc;
+use kube::api::Patch;
+use kube::{Api, Client};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::apply::patch_params;
+use crate::crd::SeroProxy;
+use crate::metrics;
+use crate::operator_config::ApplySettings;
+use crate::sero_config::{DriftPolicy, SeroConfig};
+
+pub const STATUS_ANNOTATION: &str = "beta.v1.sero/status";
+
+pub const READY: &str = "Ready";
+pub const CONFIG_VALID: &str = "ConfigValid";
+pub const SERVICE_FOUND: &str = "ServiceFound";
+pub const DEGRADED: &str = "Degraded";
+pub const DRIFTED: &str = "Drifted";
+
+/// Observed state of a sero instance.
+///
+/// Used as status subresource of a SeroProxy and serialized into
+/// the `beta.v1.sero/status` annotation of annotated deployments.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SeroStatus {
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    /// generation of the deployment or SeroProxy the status was computed for
+    pub observed_generation: Option<i64>,
+    /// sero image in use
+    pub image: Option<String>,
+    /// error of the last reconcile, if any
+    pub last_error: Option<String>,
+    /// desired hash of every generated object by kind as last applied, tells deleted objects from new ones
+    #[serde(default)]
+    pub applied: BTreeMap<String, String>,
+}
+
+impl SeroStatus {
+    /// Starts a new status based on the previous one, so unchanged conditions keep their transition time.
+    pub fn new(generation: Option<i64>, previous: Option<SeroStatus>) -> SeroStatus {
+        let previous = previous.unwrap_or_default();
+        SeroStatus {
+            conditions: previous.conditions,
+            observed_generation: generation,
+            image: None,
+            last_error: None,
+            applied: previous.applied,
+        }
+    }
+
+    pub fn set_condition(&mut self, type_: &str, ok: bool, reason: &str, message: impl Into<String>) {
+        let status = String::from(if ok { "True" } else { "False" });
+        let last_transition_time = match self.conditions.iter().find(|c| c.type_ == type_) {
+            Some(c) if c.status == status => c.last_transition_time.clone(),
+            _ => Time(Utc::now()),
+        };
+        let condition = Condition {
+            type_: type_.to_string(),
+            status,
+            reason: reason.to_string(),
+            message: message.into(),
+            observed_generation: self.observed_generation,
+            last_transition_time,
+        };
+        match self.conditions.iter_mut().find(|c| c.type_ == type_) {
+            Some(c) => *c = condition,
+            None => self.conditions.push(condition),
+        }
+    }
+
+    pub fn is_true(&self, type_: &str) -> bool {
+        self.conditions.iter().any(|c| c.type_ == type_ && c.status == "True")
+    }
+
+    pub fn invalid_config(&mut self, e: &anyhow::Error) {
+        self.set_condition(CONFIG_VALID, false, "InvalidConfig", e.to_string());
+        self.set_condition(SERVICE_FOUND, false, "InvalidConfig", "");
+        self.set_condition(READY, false, "InvalidConfig", "");
+        self.fail(e);
+    }
+
+    /// Another instance targets the same deployment, so this one isn't applied.
+    pub fn conflict(&mut self, message: String) {
+        self.set_condition(READY, false, "Conflict", message.clone());
+        self.last_error = Some(message.clone());
+        self.set_condition(DEGRADED, true, "Conflict", message);
+    }
+
+    pub fn fail(&mut self, e: &anyhow::Error) {
+        self.last_error = Some(e.to_string());
+        self.set_condition(DEGRADED, true, "ReconcileError", e.to_string());
+    }
+
+    /// Records the outcome of applying a sero instance and checks what came up.
+    pub async fn observe(&mut self, client: &Client, sero_config: &SeroConfig, invalid: &[String], applied: Result<()>) {
+        self.image = Some(sero_config.image.clone());
+        if invalid.is_empty() {
+            self.set_condition(CONFIG_VALID, true, "Valid", "");
+        } else {
+            self.set_condition(CONFIG_VALID, false, "InvalidAnnotation",
+                format!("using defaults for {}", invalid.join(", ")));
+        }
+        if let Err(e) = applied {
+            self.fail(&e);
+        }
+        if let Err(e) = self.probe(client, sero_config).await {
+            self.fail(&e);
+        }
+        if self.last_error.is_none() {
+            self.set_condition(DEGRADED, false, "ReconcileSucceeded", "");
+        }
+    }
+
+    /// Records which generated objects differed from the desired state.
+    pub fn drifted(&mut self, sero_config: &SeroConfig, kinds: &[String]) {
+        match (kinds.is_empty(), sero_config.drift) {
+            (true, _) => self.set_condition(DRIFTED, false, "InSync", ""),
+            (false, DriftPolicy::Repair) => self.set_condition(DRIFTED, false, "Repaired",
+                format!("repaired {}", kinds.join(", "))),
+            (false, DriftPolicy::Report) => self.set_condition(DRIFTED, true, "Drifted",
+                format!("{} of {} differ from the desired state", kinds.join(", "), sero_config.name_patern())),
+        }
+    }
+
+    async fn probe(&mut self, client: &Client, sero_config: &SeroConfig) -> Result<()> {
+        let svc: Api<Service> = Api::namespaced(client.clone(), &sero_config.namespace);
+        match svc.get_opt(&sero_config.service).await.inspect_err(|_| metrics::api_error("Service", "get"))? {
+            Some(_) => self.set_condition(SERVICE_FOUND, true, "ServiceFound", ""),
+            None => self.set_condition(SERVICE_FOUND, false, "ServiceNotFound",
+                format!("service {} does not exist", sero_config.service)),
+        }
+
+        let deploy: Api<Deployment> = Api::namespaced(client.clone(), &sero_config.namespace);
+        let available = deploy.get_opt(&sero_config.name_patern()).await.inspect_err(|_| metrics::api_error("Deployment", "get"))?
+            .and_then(|d| d.status)
+            .and_then(|s| s.available_replicas)
+            .unwrap_or(0);
+        if available > 0 {
+            self.set_condition(READY, true, "Available", "");
+        } else {
+            self.set_condition(READY, false, "Unavailable",
+                format!("deployment {} has no available replicas", sero_config.name_patern()));
+        }
+        Ok(())
+    }
+}
+
+pub fn from_annotations(annotations: Option<&BTreeMap<String, String>>) -> Option<SeroStatus> {
+    annotations
+        .and_then(|a| a.get(STATUS_ANNOTATION))
+        .and_then(|s| serde_json::from_str(s).ok())
+}
+
+/// Writes the status annotation of a deployment, skipping the write if nothing changed.
+pub async fn patch_deployment(client: &Client, name: &str, namespace: &str, previous: Option<SeroStatus>, status: SeroStatus, apply: &ApplySettings) -> Result<()> {
+    if previous.as_ref() == Some(&status) {
+        return Ok(());
+    }
+    let deploy: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    let patch = json!({
+        "metadata": {"annotations": {STATUS_ANNOTATION: serde_json::to_string(&status)?}}
+    });
+    if let Some(params) = patch_params(apply, "patch", "Deployment", namespace, name) {
+        deploy.patch(name, &params, &Patch::Merge(&patch)).await.inspect_err(|_| metrics::api_error("Deployment", "patch"))?;
+    }
+    Ok(())
+}
+
+pub async fn clear_deployment(client: &Client, name: &str, namespace: &str, apply: &ApplySettings) -> Result<()> {
+    let deploy: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    let patch = json!({
+        "metadata": {"annotations": {STATUS_ANNOTATION: null}}
+    });
+    if let Some(params) = patch_params(apply, "patch", "Deployment", namespace, name) {
+        deploy.patch(name, &params, &Patch::Merge(&patch)).await.inspect_err(|_| metrics::api_error("Deployment", "patch"))?;
+    }
+    Ok(())
+}
+
+/// Writes the status subresource of a SeroProxy, skipping the write if nothing changed.
+pub async fn patch_proxy(client: &Client, name: &str, namespace: &str, previous: Option<SeroStatus>, status: SeroStatus, apply: &ApplySettings) -> Result<()> {
+    if previous.as_ref() == Some(&status) {
+        return Ok(());
+    }
+    let proxies: Api<SeroProxy> = Api::namespaced(client.clone(), namespace);
+    let patch = json!({ "status": status });
+    if let Some(params) = patch_params(apply, "patch", "SeroProxy/status", namespace, name) {
+        proxies.patch_status(name, &params, &Patch::Merge(&patch)).await
+            .inspect_err(|_| metrics::api_error("SeroProxy", "patch_status"))?;
+    }
+    Ok(())
+}
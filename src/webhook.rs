@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use axum::{extract::State, routing::post, Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use k8s_openapi::api::apps::v1::Deployment;
+use kube::core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview, Operation};
+use kube::core::DynamicObject;
+use kube::ResourceExt;
+use tracing::{info, warn};
+
+use crate::api::annotation::{get_type, AppType, OPERATOR_ANNOTATIONS};
+use crate::certs;
+use crate::controller::Context;
+use crate::apply::dry_run;
+use crate::operator_config::{DefaultSeroConfig, Settings, WebhookSettings};
+use crate::to_config;
+
+/// Serves the validating webhook for sero annotations on deployments over https.
+pub async fn serve(settings: WebhookSettings, ctx: Arc<Context>) -> anyhow::Result<()> {
+    let tls = match settings.self_signed {
+        true => {
+            let certs = certs::ensure(&ctx.client, &settings).await?;
+            let tls = RustlsConfig::from_pem(certs.cert.clone().into_bytes(), certs.key.clone().into_bytes()).await?;
+            tokio::spawn(certs::rotate(ctx.client.clone(), settings.clone(), tls.clone(), certs));
+            tls
+        },
+        false => {
+            let dir = Path::new(&settings.cert_dir);
+            RustlsConfig::from_pem_file(dir.join("tls.crt"), dir.join("tls.key")).await?
+        },
+    };
+    let app = Router::new()
+        .route("/validate", post(validate))
+        .with_state(ctx);
+    let addr = SocketAddr::from(([0, 0, 0, 0], settings.port));
+    info!("serving webhook on {}", addr);
+    axum_server::bind_rustls(addr, tls)
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+async fn validate(State(ctx): State<Arc<Context>>, Json(review): Json<AdmissionReview<Deployment>>) -> Json<AdmissionReview<DynamicObject>> {
+    let req: AdmissionRequest<Deployment> = match review.try_into() {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("invalid admission review: {}", e);
+            return Json(AdmissionResponse::invalid(e).into_review());
+        },
+    };
+    let mut res = AdmissionResponse::from(&req);
+    // deletes come without an object
+    let d = match &req.object {
+        Some(v) => v,
+        None => return Json(res.into_review()),
+    };
+    if !needs_check(&req.operation, req.old_object.as_ref(), d) {
+        return Json(res.into_review());
+    }
+    let namespace = req.namespace.clone().unwrap_or_default();
+    let settings = ctx.settings();
+    let problems = problems(d, namespace.clone(), settings.default_config.clone());
+    if problems.is_empty() {
+        return Json(res.into_review());
+    }
+    info!("deployment {}/{} has invalid sero annotations: {}", namespace, req.name, problems.join(", "));
+    if rejects(&settings, &namespace, &req.name) {
+        res = res.deny(format!("invalid sero annotations: {}", problems.join(", ")));
+    } else {
+        res.warnings = Some(problems);
+    }
+    Json(res.into_review())
+}
+
+/// Whether a deployment with invalid annotations is denied, in dry-run mode the denial is only recorded
+/// and the problems are returned as warnings.
+fn rejects(settings: &Settings, namespace: &str, name: &str) -> bool {
+    settings.webhook.reject && dry_run(&settings.apply, "deny", "Deployment", namespace, name) == Some(false)
+}
+
+/// Whether an admitted deployment has to be validated.
+///
+/// Deployments being deleted and updates that leave the user's sero annotations alone
+/// (finalizers, operator annotations, scaling) are always admitted, so a bad annotation
+/// can't block the teardown.
+pub fn needs_check(operation: &Operation, old: Option<&Deployment>, d: &Deployment) -> bool {
+    if d.metadata.deletion_timestamp.is_some() {
+        return false;
+    }
+    match (operation, old) {
+        (Operation::Create, _) => true,
+        (Operation::Update, Some(old)) => sero_annotations(old) != sero_annotations(d),
+        _ => false,
+    }
+}
+
+// the annotations set by the user, not the ones the operator writes
+fn sero_annotations(d: &Deployment) -> BTreeMap<&String, &String> {
+    d.annotations().iter()
+        .filter(|(k, _)| k.starts_with("beta.v1.sero/") && !OPERATOR_ANNOTATIONS.contains(&k.as_str()))
+        .collect()
+}
+
+/// Runs the annotations of a deployment through the same parsing the controller uses.
+pub fn problems(d: &Deployment, namespace: String, default: DefaultSeroConfig) -> Vec<String> {
+    if !matches!(get_type(d.annotations()), AppType::Managed) {
+        return vec![];
+    }
+    // created deployments may only have a generateName yet
+    let name = d.metadata.name.clone()
+        .or_else(|| d.metadata.generate_name.clone())
+        .unwrap_or_default();
+    match to_config(d.annotations().clone(), name, namespace, default) {
+        Ok((_, invalid)) => invalid.into_iter().map(|i| format!("can't use {}", i)).collect(),
+        Err(e) => vec![e.to_string()],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+    use k8s_openapi::chrono::Utc;
+    use kube::core::ObjectMeta;
+
+    use super::*;
+    use crate::finalizer::{FINALIZER, ORIGINAL_REPLICAS};
+    use crate::operator_config::DryRun;
+
+    fn deployment(annotations: &[(&str, &str)], finalizers: &[&str]) -> Deployment {
+        Deployment {
+            metadata: ObjectMeta {
+                name: Some(String::from("app")),
+                annotations: Some(annotations.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()),
+                finalizers: Some(finalizers.iter().map(|f| f.to_string()).collect()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn invalid_annotations_are_only_denied_without_dry_run() {
+        let mut settings = Settings::default();
+        assert!(rejects(&settings, "apps", "app"));
+        settings.webhook.reject = false;
+        assert!(!rejects(&settings, "apps", "app"));
+        settings.webhook.reject = true;
+        settings.apply.dry_run = DryRun::Skip;
+        assert!(!rejects(&settings, "apps", "app"));
+        settings.apply.dry_run = DryRun::Server;
+        assert!(!rejects(&settings, "apps", "app"));
+    }
+
+    #[test]
+    fn created_deployments_are_checked() {
+        let d = deployment(&[("beta.v1.sero/inject", "yes")], &[]);
+        assert!(needs_check(&Operation::Create, None, &d));
+    }
+
+    #[test]
+    fn changed_sero_annotations_are_checked() {
+        let old = deployment(&[("beta.v1.sero/inject", "true")], &[]);
+        let d = deployment(&[("beta.v1.sero/inject", "yes")], &[]);
+        assert!(needs_check(&Operation::Update, Some(&old), &d));
+    }
+
+    #[test]
+    fn finalizer_removal_is_admitted_despite_bad_annotations() {
+        let annotations = [("beta.v1.sero/timeout-scaledown", "1000"), (ORIGINAL_REPLICAS, "2")];
+        let old = deployment(&annotations, &[FINALIZER]);
+        let d = deployment(&annotations[..1], &[]);
+        assert!(!needs_check(&Operation::Update, Some(&old), &d));
+    }
+
+    #[test]
+    fn deleted_deployments_are_admitted() {
+        let old = deployment(&[], &[FINALIZER]);
+        let mut d = deployment(&[("beta.v1.sero/inject", "yes")], &[FINALIZER]);
+        d.metadata.deletion_timestamp = Some(Time(Utc::now()));
+        assert!(!needs_check(&Operation::Update, Some(&old), &d));
+    }
+}